@@ -0,0 +1,83 @@
+; Startup code of the application processors. smp.rs copies it below 1 MiB (to AP_TRAMPOLINE)
+; and points the APs there with a startup IPI: they start in real mode, go through protected
+; mode to long mode with the kernel's page table and jump to ap_start with the values the BSP
+; wrote after ap_trampoline_data.
+
+global ap_trampoline_start
+global ap_trampoline_data
+global ap_trampoline_end
+
+AP_TRAMPOLINE equ 0x8000 ; must match smp.rs, page aligned
+%define LOW(x) (AP_TRAMPOLINE + (x) - ap_trampoline_start) ; address of x in the copy
+
+section .rodata ; never run from here, only copied
+bits 16
+
+ap_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    lgdt [LOW(ap_gdt_pointer)]
+    mov eax, cr0
+    or eax, 1 ; protected mode
+    mov cr0, eax
+    jmp dword 0x08:LOW(ap_protected_mode)
+
+bits 32
+
+ap_protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov eax, cr4
+    or eax, 1 << 5 ; PAE
+    mov cr4, eax
+    mov eax, [LOW(ap_cr3)]
+    mov cr3, eax
+    mov ecx, 0xC0000080 ; EFER, same as the BSP's (long mode, syscalls, NX)
+    mov eax, [LOW(ap_efer)]
+    xor edx, edx
+    wrmsr
+    mov eax, cr0
+    or eax, 1 << 31 ; paging
+    mov cr0, eax
+    jmp 0x18:LOW(ap_long_mode)
+
+bits 64
+
+ap_long_mode:
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov rsp, [LOW(ap_stack)]
+    mov rdi, [LOW(ap_cpu)] ; first argument of ap_start
+    mov rax, [LOW(ap_entry)]
+    push 0 ; no return address, keeps the stack aligned like after a call
+    jmp rax
+
+align 8
+ap_gdt:
+    dq 0
+    dq 0x00CF9A000000FFFF ; 0x08: 32 bit code
+    dq 0x00CF92000000FFFF ; 0x10: data
+    dq 0x00209A0000000000 ; 0x18: 64 bit code
+ap_gdt_pointer:
+    dw ap_gdt_pointer - ap_gdt - 1
+    dd LOW(ap_gdt)
+
+align 8
+ap_trampoline_data: ; filled in by the BSP for each AP, in this order
+ap_cr3:
+    dq 0
+ap_efer:
+    dq 0
+ap_stack:
+    dq 0
+ap_entry:
+    dq 0
+ap_cpu:
+    dq 0
+ap_trampoline_end:
@@ -2,6 +2,8 @@ extern ua64_mode_start
 
 global _start
 
+KERNEL_OFFSET equ 0xFFFFFFFF80000000 ; the kernel is linked at -2 GiB + 1 MiB
+
 section .multiboot_header
 header_start:
     dd 0xe85250d6                ; multiboot 2 magic number
@@ -27,7 +29,7 @@ _start:
     mov eax, _gdt64_pointer_low
     lgdt [eax]
     pop ebx
-    call _ua64_mode_entry
+    jmp _gdt64_code_off:_ua64_mode_entry_low ; far jump to 64 bit code, still running from the identity map
 
 _boot_error:
     mov dword [0xb8000], 0x4f524f45
@@ -101,22 +103,36 @@ _long_mode_check:
         jmp _boot_error
 
 _setup_page_table:
-    ; map first P4 entry to P3 table
-        mov eax, _p3_table_low
-        or eax, 7 ; present + writable + user
+    ; identity map the first GiB through the first P4 entry so we can keep running after
+    ; enabling paging (removed once we jump to the higher half)
+        mov eax, _p3_table_id_low
+        or eax, 3 ; present + writable
         mov [_p4_table_low], eax
+        mov eax, _p2_table_0_low
+        or eax, 3 ; present + writable
+        mov [_p3_table_id_low], eax
 
-        ; map first P3 entry to P2 table
+    ; map the first 4 GiB of physical memory at the start of the higher half (P4 entry 256)
+        mov eax, _p3_table_phys_low
+        or eax, 3 ; present + writable
+        mov [_p4_table_low + 256 * 8], eax
         mov eax, _p2_table_0_low
         or eax, 3 ; present + writable
-        mov [_p3_table_low], eax
-        mov [_p3_table_low + 24], eax
+        mov [_p3_table_phys_low], eax
         add eax, 4096
-        mov [_p3_table_low + 32], eax
+        mov [_p3_table_phys_low + 8], eax
         add eax, 4096
-        mov [_p3_table_low + 40], eax
+        mov [_p3_table_phys_low + 16], eax
         add eax, 4096
-        mov [_p3_table_low + 48], eax
+        mov [_p3_table_phys_low + 24], eax
+
+    ; map the first GiB again at -2 GiB (P4 entry 511, P3 entry 510) which is where the kernel is linked
+        mov eax, _p3_table_kernel_low
+        or eax, 3 ; present + writable
+        mov [_p4_table_low + 511 * 8], eax
+        mov eax, _p2_table_0_low
+        or eax, 3 ; present + writable
+        mov [_p3_table_kernel_low + 510 * 8], eax
 
         ; map each P2 entry to a huge 2MiB page
         mov ecx, 0         ; counter variable
@@ -132,7 +148,7 @@ _setup_page_table:
         add esi, edi
         mov dword [esi], eax     ; map ecx-th entry
         inc ecx            ; increase counter
-        cmp ecx, 2048      ; if counter == 512, the whole P2 table is mapped
+        cmp ecx, 2048      ; if counter == 2048, all four P2 tables are mapped
         jne .map_p2_table  ; else map the next entry
 
         ret
@@ -164,15 +180,24 @@ _enable_paging:
 
     ret
 
+bits 64
+
 _ua64_mode_entry:
-    mov edx, 0xC0000000
-    add esp, edx
-    mov eax, _ua64_mode_entry_high
-    jmp eax
+    mov esp, esp ; upper half of the registers is undefined after switching to long mode
+    mov rax, KERNEL_OFFSET
+    add rsp, rax ; move the stack to the higher half
+    mov rax, _ua64_mode_entry_high
+    jmp rax
     _ua64_mode_entry_high:
-    mov dword [_p3_table], 0
+    mov rax, _gdt64_pointer
+    lgdt [rax] ; reload the GDT from its higher half address
+    mov rax, _p4_table
+    mov qword [rax], 0 ; drop the identity map, the lower half is left for user space
+    mov rax, cr3
+    mov cr3, rax ; flush the TLB
     mov edi, ebx
-    jmp _gdt64_code_off:ua64_mode_start
+    mov rax, ua64_mode_start
+    jmp rax
 
 section .rodata
 gdt64:
@@ -182,13 +207,20 @@ _gdt64_code_off: equ $ - gdt64 ; new
 _gdt64_pointer:
     dw $ - gdt64 - 1
     dq gdt64
-_gdt64_pointer_low: equ _gdt64_pointer - 0xC0000000
+_gdt64_pointer_low_struct:
+    dw _gdt64_pointer - gdt64 - 1
+    dq gdt64 - KERNEL_OFFSET ; lgdt in 32 bit mode can only use a low address
+_gdt64_pointer_low: equ _gdt64_pointer_low_struct - KERNEL_OFFSET
 
 section .bss
 align 4096
 _p4_table:
     resb 4096
-_p3_table:
+_p3_table_id:
+    resb 4096
+_p3_table_phys:
+    resb 4096
+_p3_table_kernel:
     resb 4096
 _p2_table_0:
     resb 4096
@@ -202,10 +234,13 @@ _stack_bottom:
     resb 1024*40
 _stack_top:
 
-_p4_table_low: equ _p4_table - 0xC0000000
-_p3_table_low: equ _p3_table - 0xC0000000
-_p2_table_0_low: equ _p2_table_0 - 0xC0000000
-_p2_table_1_low: equ _p2_table_1 - 0xC0000000
-_p2_table_2_low: equ _p2_table_2 - 0xC0000000
-_p2_table_3_low: equ _p2_table_3 - 0xC0000000
-_stack_top_low: equ _stack_top - 0xC0000000
+_p4_table_low: equ _p4_table - KERNEL_OFFSET
+_p3_table_id_low: equ _p3_table_id - KERNEL_OFFSET
+_p3_table_phys_low: equ _p3_table_phys - KERNEL_OFFSET
+_p3_table_kernel_low: equ _p3_table_kernel - KERNEL_OFFSET
+_p2_table_0_low: equ _p2_table_0 - KERNEL_OFFSET
+_p2_table_1_low: equ _p2_table_1 - KERNEL_OFFSET
+_p2_table_2_low: equ _p2_table_2 - KERNEL_OFFSET
+_p2_table_3_low: equ _p2_table_3 - KERNEL_OFFSET
+_stack_top_low: equ _stack_top - KERNEL_OFFSET
+_ua64_mode_entry_low: equ _ua64_mode_entry - KERNEL_OFFSET
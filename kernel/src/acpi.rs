@@ -0,0 +1,149 @@
+use crate::mem::PhysAddr;
+use crate::serial_println;
+use alloc::vec::Vec;
+use multiboot2::BootInformation;
+
+// Just enough ACPI to find the interrupt controllers: the RSDP (from the bootloader or the BIOS
+// area) points to the RSDT / XSDT, which lists the tables, of which we only read the MADT.
+
+const SDT_HEADER_SIZE: usize = 36;
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub addr: usize,
+    pub gsi_base: u32, // first global system interrupt it handles
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IrqOverride {
+    pub irq: u8,    // ISA IRQ
+    pub gsi: u32,   // the global system interrupt it's connected to instead
+    pub flags: u16, // polarity in bits 0-1 and trigger mode in bits 2-3 (0 means the bus default)
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub lapic_addr: usize,
+    pub has_pics: bool,   // there are 8259 PICs too (that have to be masked)
+    pub cpus: Vec<u8>,    // local APIC ids of the enabled CPUs
+    pub ioapics: Vec<IoApicInfo>,
+    pub overrides: Vec<IrqOverride>,
+}
+
+unsafe fn phys_slice(addr: usize, len: usize) -> Option<&'static [u8]> {
+    let start = PhysAddr::new(addr).to_virt()?;
+    PhysAddr::new(addr.checked_add(len)?).to_virt()?; // all of it has to be mapped
+    Some(core::slice::from_raw_parts(start.addr() as *const u8, len))
+}
+
+fn read_u32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+fn read_u64(b: &[u8], off: usize) -> u64 {
+    read_u32(b, off) as u64 | (read_u32(b, off + 4) as u64) << 32
+}
+
+fn checksum_ok(b: &[u8]) -> bool {
+    b.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+unsafe fn table(addr: usize) -> Option<&'static [u8]> {
+    // a system description table at addr, checked
+    let header = phys_slice(addr, SDT_HEADER_SIZE)?;
+    let len = read_u32(header, 4) as usize;
+    let table = phys_slice(addr, len.max(SDT_HEADER_SIZE))?;
+    if checksum_ok(table) {
+        Some(table)
+    } else {
+        serial_println!("ACPI: bad checksum of table at {:x}", addr);
+        None
+    }
+}
+
+unsafe fn scan_rsdp(start: usize, end: usize) -> Option<(usize, bool)> {
+    // the RSDP is on a 16 byte boundary in the BIOS area, returns the RSDT or XSDT address and
+    // whether it's an XSDT
+    let area = phys_slice(start, end - start)?;
+    for off in (0..area.len() - 36).step_by(16) {
+        if &area[off..off + 8] != b"RSD PTR " || !checksum_ok(&area[off..off + 20]) {
+            continue;
+        }
+        if area[off + 15] >= 2 && checksum_ok(&area[off..off + 36]) {
+            return Some((read_u64(area, off + 24) as usize, true));
+        }
+        return Some((read_u32(area, off + 16) as usize, false));
+    }
+    None
+}
+
+unsafe fn root_table(boot_info: &BootInformation) -> Option<(usize, bool)> {
+    if let Some(rsdp) = boot_info.rsdp_v2_tag() {
+        return Some((rsdp.xsdt_address(), true));
+    }
+    if let Some(rsdp) = boot_info.rsdp_v1_tag() {
+        return Some((rsdp.rsdt_address(), false));
+    }
+    // not from the bootloader, look in the first KiB of the EBDA and then the BIOS ROM
+    let ebda = phys_slice(0x40E, 2).map_or(0, |b| (u16::from_le_bytes([b[0], b[1]]) as usize) << 4);
+    if ebda != 0 {
+        if let Some(res) = scan_rsdp(ebda, ebda + 0x400) {
+            return Some(res);
+        }
+    }
+    scan_rsdp(0xE0000, 0x100000)
+}
+
+unsafe fn find_table(boot_info: &BootInformation, signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let (root_addr, xsdt) = root_table(boot_info)?;
+    let root = table(root_addr)?;
+    let entry_size = if xsdt { 8 } else { 4 };
+    (SDT_HEADER_SIZE..root.len())
+        .step_by(entry_size)
+        .filter(|off| off + entry_size <= root.len())
+        .map(|off| if xsdt { read_u64(root, off) as usize } else { read_u32(root, off) as usize })
+        .filter_map(|addr| table(addr))
+        .find(|table| &table[..4] == signature)
+}
+
+/// # Safety
+/// The physical memory map must be set up (see init_phys_map).
+pub unsafe fn find_madt(boot_info: &BootInformation) -> Option<Madt> {
+    // the interrupt controllers and CPUs, None if there's no ACPI or no MADT
+    let table = find_table(boot_info, MADT_SIGNATURE)?;
+    let mut madt = Madt {
+        lapic_addr: read_u32(table, SDT_HEADER_SIZE) as usize,
+        has_pics: read_u32(table, SDT_HEADER_SIZE + 4) & 1 != 0,
+        cpus: Vec::new(),
+        ioapics: Vec::new(),
+        overrides: Vec::new(),
+    };
+    let mut off = SDT_HEADER_SIZE + 8;
+    while off + 2 <= table.len() {
+        let (typ, len) = (table[off], table[off + 1] as usize);
+        if len < 2 || off + len > table.len() {
+            break; // broken table, keep what we have
+        }
+        let entry = &table[off..off + len];
+        match typ {
+            0 if len >= 8 && read_u32(entry, 4) & 1 != 0 => madt.cpus.push(entry[3]), // enabled local APIC
+            1 if len >= 12 => madt.ioapics.push(IoApicInfo {
+                id: entry[2],
+                addr: read_u32(entry, 4) as usize,
+                gsi_base: read_u32(entry, 8),
+            }),
+            2 if len >= 10 => madt.overrides.push(IrqOverride {
+                irq: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: u16::from_le_bytes([entry[8], entry[9]]),
+            }),
+            5 if len >= 12 => madt.lapic_addr = read_u64(entry, 4) as usize, // 64 bit local APIC address
+            _ => {}
+        }
+        off += len;
+    }
+    serial_println!("ACPI: {:x?}", madt);
+    Some(madt)
+}
@@ -0,0 +1,208 @@
+use crate::acpi::{self, Madt};
+use crate::mem::PhysAddr;
+use crate::port;
+use crate::serial_println;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use multiboot2::BootInformation;
+
+// Local APIC and IO-APIC support. When the MADT lists them, the 8259 PICs are masked, the IO-APIC
+// delivers the device IRQs on the same vectors the PICs used and the local APIC's timer replaces
+// the PIT for scheduling. Without them everything keeps going through the PICs.
+
+// local APIC registers
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS: usize = 0xF0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+const TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_DIVIDE_16: u32 = 3;
+const CALIBRATION_MS: u64 = 10;
+
+pub const TIMER_VECTOR: u8 = 32; // same as the PIT's IRQ 0 on the PIC
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+const IRQ_BASE_VECTOR: u8 = 32; // ISA IRQ n is delivered on vector 32 + n, like the remapped PICs
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    Periodic, // the timer reloads itself
+    OneShot,  // the timer is armed again on every tick, so the next one could be moved
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LAPIC_BASE: AtomicUsize = AtomicUsize::new(0); // virtual address of the local APIC's registers
+static IOAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+static IOAPIC_GSI_BASE: AtomicU32 = AtomicU32::new(0);
+static ONESHOT_COUNT: AtomicU32 = AtomicU32::new(0); // initial count to rearm the one shot timer with, 0 if periodic
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0); // the BSP's timer settings, for the APs
+static TIMER_LVT: AtomicU32 = AtomicU32::new(LVT_MASKED);
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+unsafe fn lapic_read(reg: usize) -> u32 {
+    core::ptr::read_volatile((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *const u32)
+}
+
+unsafe fn lapic_write(reg: usize, val: u32) {
+    core::ptr::write_volatile((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *mut u32, val);
+}
+
+unsafe fn ioapic_write(reg: u32, val: u32) {
+    let base = IOAPIC_BASE.load(Ordering::Relaxed);
+    core::ptr::write_volatile(base as *mut u32, reg); // IOREGSEL
+    core::ptr::write_volatile((base + 0x10) as *mut u32, val); // IOWIN
+}
+
+unsafe fn ioapic_read(reg: u32) -> u32 {
+    let base = IOAPIC_BASE.load(Ordering::Relaxed);
+    core::ptr::write_volatile(base as *mut u32, reg);
+    core::ptr::read_volatile((base + 0x10) as *const u32)
+}
+
+pub fn lapic_id() -> u8 {
+    unsafe { (lapic_read(LAPIC_ID) >> 24) as u8 }
+}
+
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+/// # Safety
+/// Only once at boot on the BSP, with interrupts off.
+pub unsafe fn init(boot_info: &BootInformation) -> Option<Madt> {
+    // enable the local APIC and route the ISA IRQs through the IO-APIC, None if there aren't any
+    // (the PICs have to be used then)
+    let has_apic = core::arch::x86_64::__cpuid(1).edx & (1 << 9) != 0;
+    if !has_apic {
+        return None;
+    }
+    let madt = acpi::find_madt(boot_info)?;
+    let ioapic = *madt.ioapics.first()?;
+    LAPIC_BASE.store(PhysAddr::new(madt.lapic_addr).to_virt()?.addr(), Ordering::SeqCst);
+    IOAPIC_BASE.store(PhysAddr::new(ioapic.addr).to_virt()?.addr(), Ordering::SeqCst);
+    IOAPIC_GSI_BASE.store(ioapic.gsi_base, Ordering::SeqCst);
+
+    enable_lapic();
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED); // until start_timer
+
+    let entries = ((ioapic_read(1) >> 16) & 0xFF) + 1; // max redirection entry + 1
+    for i in 0..entries {
+        ioapic_write(0x10 + 2 * i, LVT_MASKED); // mask everything, then unmask what we handle
+    }
+    for irq in [1u8, 14].iter() {
+        route_irq(&madt, *irq); // keyboard and IDE
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+    serial_println!("APIC: local APIC {} at {:x}, IO-APIC {} at {:x} with {} entries",
+        lapic_id(), madt.lapic_addr, ioapic.id, ioapic.addr, entries);
+    Some(madt)
+}
+
+unsafe fn enable_lapic() {
+    let mut apic_base = x86_64::registers::model_specific::Msr::new(0x1B);
+    let val = apic_base.read();
+    apic_base.write(val | (1 << 11)); // global enable, in case the firmware didn't
+    lapic_write(LAPIC_SPURIOUS, (1 << 8) | SPURIOUS_VECTOR as u32); // software enable
+}
+
+/// # Safety
+/// Only once on each AP, with interrupts off.
+pub unsafe fn init_ap() {
+    // enable the local APIC of an AP and start its timer the same way as the BSP's
+    enable_lapic();
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic_write(LAPIC_LVT_TIMER, TIMER_LVT.load(Ordering::SeqCst));
+    lapic_write(LAPIC_TIMER_INITIAL, TIMER_COUNT.load(Ordering::SeqCst));
+}
+
+unsafe fn send_ipi(lapic_id: u8, low: u32) {
+    lapic_write(LAPIC_ICR_HIGH, (lapic_id as u32) << 24);
+    lapic_write(LAPIC_ICR_LOW, low);
+    while lapic_read(LAPIC_ICR_LOW) & (1 << 12) != 0 {} // delivery pending
+}
+
+/// # Safety
+/// lapic_id must be a CPU that's not running yet.
+pub unsafe fn send_init(lapic_id: u8) {
+    send_ipi(lapic_id, 0x4500); // INIT, level assert
+}
+
+/// # Safety
+/// lapic_id must be a CPU that just got an INIT and page must hold the AP trampoline.
+pub unsafe fn send_startup(lapic_id: u8, page: u8) {
+    send_ipi(lapic_id, 0x4600 | page as u32); // startup at page * 4 KiB in real mode
+}
+
+/// # Safety
+/// vector must have a handler on the target CPU.
+pub unsafe fn send_fixed(lapic_id: u8, vector: u8) {
+    send_ipi(lapic_id, 0x4000 | vector as u32); // a normal interrupt on that CPU
+}
+
+unsafe fn route_irq(madt: &Madt, irq: u8) {
+    // send an ISA IRQ to this CPU, through whatever global system interrupt it's connected to
+    let (gsi, flags) = madt
+        .overrides
+        .iter()
+        .find(|o| o.irq == irq)
+        .map_or((irq as u32, 0), |o| (o.gsi, o.flags));
+    let pin = match gsi.checked_sub(IOAPIC_GSI_BASE.load(Ordering::Relaxed)) {
+        Some(pin) => pin,
+        None => return,
+    };
+    let mut low = (IRQ_BASE_VECTOR + irq) as u32; // fixed delivery, physical destination
+    if flags & 3 == 3 {
+        low |= 1 << 13; // active low
+    }
+    if (flags >> 2) & 3 == 3 {
+        low |= 1 << 15; // level triggered
+    }
+    ioapic_write(0x10 + 2 * pin + 1, (lapic_id() as u32) << 24);
+    ioapic_write(0x10 + 2 * pin, low);
+}
+
+/// # Safety
+/// Only on a CPU whose local APIC is enabled, with interrupts off.
+pub unsafe fn start_timer(hz: u64, mode: TimerMode) -> u64 {
+    // Fire the timer vector hz times a second, counted against the PIT. Returns the actual
+    // length of a tick in ns.
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    port::pit_wait((port::PIT_HZ * CALIBRATION_MS / 1000) as u16);
+    let per_ms = (u32::MAX - lapic_read(LAPIC_TIMER_CURRENT)) as u64 / CALIBRATION_MS;
+    lapic_write(LAPIC_TIMER_INITIAL, 0);
+    let count = (per_ms * 1000 / hz.max(1)).clamp(1, u32::MAX as u64) as u32;
+    serial_println!("APIC: timer runs at {} kHz, {} counts per tick ({:?})", per_ms, count, mode);
+    let lvt = match mode {
+        TimerMode::Periodic => {
+            ONESHOT_COUNT.store(0, Ordering::SeqCst);
+            TIMER_PERIODIC | TIMER_VECTOR as u32
+        }
+        TimerMode::OneShot => {
+            ONESHOT_COUNT.store(count, Ordering::SeqCst);
+            TIMER_VECTOR as u32
+        }
+    };
+    TIMER_LVT.store(lvt, Ordering::SeqCst);
+    TIMER_COUNT.store(count, Ordering::SeqCst);
+    lapic_write(LAPIC_LVT_TIMER, lvt);
+    lapic_write(LAPIC_TIMER_INITIAL, count);
+    count as u64 * 1_000_000 / per_ms.max(1)
+}
+
+pub fn rearm_timer() {
+    // called on every tick, the one shot timer needs to be started again
+    let count = ONESHOT_COUNT.load(Ordering::Relaxed);
+    if count != 0 {
+        unsafe { lapic_write(LAPIC_TIMER_INITIAL, count) };
+    }
+}
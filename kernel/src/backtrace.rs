@@ -0,0 +1,22 @@
+use crate::mem::PHYS_MAP_OFFSET;
+use core::arch::asm;
+
+#[inline(always)]
+pub fn backtrace<const N: usize>() -> [usize; N] {
+    // Walk the saved rbp chain: each frame starts with the caller's rbp followed by the return
+    // address. Stop as soon as rbp doesn't look like a kernel stack address (ie. user rbp after
+    // a syscall or the end of the chain). Needs the kernel to be built with frame pointers.
+    let mut callers = [0; N];
+    let mut rbp: usize;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp);
+        for caller in callers.iter_mut() {
+            if rbp < PHYS_MAP_OFFSET || !rbp.is_multiple_of(8) {
+                break;
+            }
+            *caller = *((rbp + 8) as *const usize);
+            rbp = *(rbp as *const usize);
+        }
+    }
+    callers
+}
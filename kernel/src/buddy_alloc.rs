@@ -2,16 +2,81 @@ use crate::frame_alloc::FrameSingleAllocator;
 use crate::mem::PhysAddr;
 use crate::mem::VirtAddr;
 use crate::mem::FRAME_SIZE;
-// use crate::serial_println;
+use crate::serial_println;
 use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::vec;
 use alloc::vec::Vec;
 use core::cmp;
 use core::fmt::Display;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::{Mutex, RwLock};
 
 pub struct BuddyAllocatorManager {
-    buddy_allocators: RwLock<Vec<Mutex<BuddyAllocator>>>,
+    buddy_allocators: RwLock<Vec<BuddyArea>>,
+    failed_allocs: AtomicUsize, // allocations that no area could serve
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BuddyStats {
+    pub start_addr: PhysAddr,
+    pub end_addr: PhysAddr,
+    pub allocated_bytes: usize, // bytes in allocated blocks (ie. including the rounding up to a block size)
+    pub peak_bytes: usize,      // max allocated_bytes ever
+    pub free_bytes: usize,      // bytes in free blocks
+    pub largest_free: usize,    // size of the largest free block
+    pub allocs: usize,          // total allocations served
+    pub frees: usize,           // total deallocations
+}
+
+impl BuddyStats {
+    pub fn fragmentation(&self) -> usize {
+        // percentage of the free memory that can't be used for the largest allocation possible, 0
+        // without any free memory
+        (self.largest_free * 100).checked_div(self.free_bytes).map_or(0, |usable| 100 - usable)
+    }
+}
+
+struct BuddyArea {
+    start_addr: PhysAddr, // copy of the allocator's bounds so that we can find the area
+    end_addr: PhysAddr,   // for a deallocation without locking it
+    allocator: Mutex<BuddyAllocator>,
+    deferred_frees: Mutex<usize>, // list of blocks freed while the allocator was locked
+}
+
+// Written into a freed block whose area is locked (ie. the dealloc happened while the area was
+// allocating, from an interrupt or from a nested allocation). The smallest block is 16 bytes so it fits.
+struct DeferredFree {
+    next: usize, // virtual address of the next deferred block (0 if none)
+    size: usize, // max(size, alignment) of the layout that was freed
+}
+
+impl BuddyArea {
+    fn contains(&self, addr: PhysAddr) -> bool {
+        addr.addr() >= self.start_addr.addr() && addr.addr() < self.end_addr.addr()
+    }
+
+    unsafe fn defer_free(&self, ptr: *mut u8, layout: Layout) {
+        let mut head = self.deferred_frees.lock();
+        let block = ptr as *mut DeferredFree;
+        block.write(DeferredFree {
+            next: *head,
+            size: cmp::max(layout.size(), layout.align()),
+        });
+        *head = ptr as usize;
+    }
+
+    unsafe fn free_deferred(&self, allocator: &mut BuddyAllocator) {
+        // take the whole list and release the lock before freeing, as freeing may allocate
+        let mut next = core::mem::replace(&mut *self.deferred_frees.lock(), 0);
+        while next != 0 {
+            let block = (next as *const DeferredFree).read();
+            if let Some((phys_addr, _)) = VirtAddr::new(next).to_phys() {
+                allocator.dealloc(phys_addr, block.size, 1);
+            }
+            next = block.next;
+        }
+    }
 }
 
 enum MemAreaRequest {
@@ -24,14 +89,71 @@ impl BuddyAllocatorManager {
     pub fn new() -> BuddyAllocatorManager {
         // Create an empty buddy allocator list. At this point we're still using the dumb page allocator.
         let buddy_allocators = RwLock::new(Vec::with_capacity(32));
-        BuddyAllocatorManager { buddy_allocators }
+        BuddyAllocatorManager {
+            buddy_allocators,
+            failed_allocs: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> Vec<BuddyStats> {
+        // Collect the stats first and allocate the result afterwards, as allocating while holding
+        // an area's lock would make the allocation skip that area.
+        let areas = self.buddy_allocators.read();
+        let mut stats = Vec::with_capacity(areas.len());
+        for area in areas.iter() {
+            let area_stats = area.allocator.lock().stats();
+            stats.push(area_stats);
+        }
+        stats
+    }
+
+    pub fn failed_allocs(&self) -> usize {
+        self.failed_allocs.load(Ordering::Relaxed)
+    }
+
+    pub fn dump(&self) {
+        // Print everything to serial. This doesn't allocate and skips busy areas so it's safe
+        // to call from anywhere (ie. a fault handler).
+        let areas = self.buddy_allocators.read();
+        serial_println!(
+            "BuddyAllocatorManager: {} areas / {} failed allocations",
+            areas.len(),
+            self.failed_allocs()
+        );
+        for (i, area) in areas.iter().enumerate() {
+            match area.allocator.try_lock() {
+                Some(allocator) => {
+                    let stats = allocator.stats();
+                    serial_println!(
+                        " #{}: {} allocated / {} peak / {} free / {} largest free / {}% fragmented / {} allocs / {} frees",
+                        i,
+                        stats.allocated_bytes,
+                        stats.peak_bytes,
+                        stats.free_bytes,
+                        stats.largest_free,
+                        stats.fragmentation(),
+                        stats.allocs,
+                        stats.frees
+                    );
+                    serial_println!("{}", *allocator);
+                }
+                None => {
+                    serial_println!(" #{}: busy", i);
+                }
+            }
+        }
     }
 
     pub fn add_memory_area(&self, start_addr: PhysAddr, end_addr: PhysAddr, block_size: u16) {
         // Add a new buddy allocator to the list with these specs.
         // As each one has some dynamic internal structures, we try to make it so that none of these
         // has to use itself when allocating these.
-        let new_buddy_alloc = Mutex::new(BuddyAllocator::new(start_addr, end_addr, block_size));
+        let new_buddy_alloc = BuddyArea {
+            start_addr,
+            end_addr,
+            allocator: Mutex::new(BuddyAllocator::new(start_addr, end_addr, block_size)),
+            deferred_frees: Mutex::new(0),
+        };
         // On creation the buddy allocator constructor might lock the list of buddy allocators
         // due to the fact that it allocates memory for its internal structures (except for the very
         // first buddy allocator which still uses the previous, dumb allocator).
@@ -40,6 +162,44 @@ impl BuddyAllocatorManager {
         self.buddy_allocators.write().push(new_buddy_alloc);
     }
 
+    pub fn try_add_memory_area(&self, start_addr: PhysAddr, end_addr: PhysAddr, block_size: u16) -> bool {
+        // Like add_memory_area, for growing the heap from inside an allocation: that allocation
+        // might come from code that is using the list of areas further up the call stack, so give
+        // up instead of waiting for it. Nothing may allocate while we hold the list's write lock
+        // so make room in the list before taking it.
+        let new_buddy_alloc = BuddyArea {
+            start_addr,
+            end_addr,
+            allocator: Mutex::new(BuddyAllocator::new(start_addr, end_addr, block_size)),
+            deferred_frees: Mutex::new(0),
+        };
+        let full_capacity = {
+            let areas = self.buddy_allocators.read();
+            if areas.len() == areas.capacity() {
+                Some(areas.capacity() * 2)
+            } else {
+                None
+            }
+        };
+        let mut bigger_list = full_capacity.map(Vec::with_capacity);
+        let old_list = match self.buddy_allocators.try_write() {
+            Some(mut areas) => {
+                let old_list = bigger_list.take().map(|mut bigger_list| {
+                    bigger_list.extend(areas.drain(..));
+                    core::mem::replace(&mut *areas, bigger_list)
+                });
+                if areas.len() == areas.capacity() {
+                    return false; // someone else filled it up in the meantime
+                }
+                areas.push(new_buddy_alloc);
+                old_list
+            }
+            None => return false,
+        };
+        drop(old_list); // free the old list after releasing the lock
+        true
+    }
+
     pub fn add_mem_area_with_size(
         &self,
         frame_alloc: &mut dyn FrameSingleAllocator,
@@ -175,9 +335,11 @@ unsafe impl GlobalAlloc for BuddyAllocatorManager {
                 .read()
                 .iter()
                 .enumerate()
-                .find_map(|(_i, allocator)| {
+                .find_map(|(_i, area)| {
                     // for each allocator
-                    allocator.try_lock().and_then(|mut allocator| {
+                    area.allocator.try_lock().and_then(|mut allocator| {
+                        // first give back anything that was freed while it was busy
+                        area.free_deferred(&mut allocator);
                         allocator
                             .alloc(layout.size(), layout.align())
                             .map(|allocation| {
@@ -192,6 +354,9 @@ unsafe impl GlobalAlloc for BuddyAllocatorManager {
                             })
                     })
                 });
+        if allocation.is_none() {
+            self.failed_allocs.fetch_add(1, Ordering::Relaxed);
+        }
         // Convert physical address to virtual if we got an allocation, otherwise return null.
         allocation
             .and_then(|phys| phys.to_virt())
@@ -202,22 +367,20 @@ unsafe impl GlobalAlloc for BuddyAllocatorManager {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let virt_addr = VirtAddr::new(ptr as usize);
         if let Some((phys_addr, _)) = virt_addr.to_phys() {
-            for (_i, allocator_mtx) in self.buddy_allocators.read().iter().enumerate() {
-                // for each allocator
-                if let Some(mut allocator) = allocator_mtx.try_lock() {
-                    // find the one whose memory range contains this address
-                    if allocator.contains(phys_addr) {
-                        // deallocate using this allocator!
-                        allocator.dealloc(phys_addr, layout.size(), layout.align());
-                        // serial_println!(
-                        //     " - BuddyAllocator #{} de-allocated {} bytes",
-                        //     i,
-                        //     layout.size()
-                        // );
-                        // serial_println!("{}", *allocator);
-                        return;
-                    }
+            // find the area whose memory range contains this address
+            if let Some(area) = self.buddy_allocators.read().iter().find(|area| area.contains(phys_addr)) {
+                if let Some(mut allocator) = area.allocator.try_lock() {
+                    // deallocate using this allocator!
+                    area.free_deferred(&mut allocator);
+                    allocator.dealloc(phys_addr, layout.size(), layout.align());
+                    // serial_println!("{}", *allocator);
+                } else {
+                    // The area is busy, possibly further up our own call stack (its free lists
+                    // are being resized), so waiting for the lock could deadlock. Queue the block
+                    // instead, it will be freed the next time the area is used.
+                    area.defer_free(ptr, layout);
                 }
+                return;
             }
         }
         // serial_println!(
@@ -233,6 +396,11 @@ struct BuddyAllocator {
     num_levels: u8,            // the number of non-leaf levels
     block_size: u16,           // the size of blocks on the leaf level
     free_lists: Vec<Vec<u32>>, // the list of free blocks on each level
+    allocated: Vec<u32>,       // number of allocated blocks on each level
+    allocated_bytes: usize,
+    peak_bytes: usize,
+    allocs: usize,
+    frees: usize,
 }
 
 impl BuddyAllocator {
@@ -252,6 +420,7 @@ impl BuddyAllocator {
         }
         // The top-most block is (the only) free for now!
         free_lists[0].push(0);
+        let allocated = vec![0; (num_levels + 1) as usize];
         // We need 1<<levels bits to store which blocks are split (so 1<<(levels-3) bytes)
         BuddyAllocator {
             start_addr,
@@ -259,12 +428,34 @@ impl BuddyAllocator {
             num_levels,
             block_size,
             free_lists,
+            allocated,
+            allocated_bytes: 0,
+            peak_bytes: 0,
+            allocs: 0,
+            frees: 0,
         }
     }
 
-    fn contains(&self, addr: PhysAddr) -> bool {
-        // whether a given physical address belongs to this allocator
-        addr.addr() >= self.start_addr.addr() && addr.addr() < self.end_addr.addr()
+    fn stats(&self) -> BuddyStats {
+        let mut free_bytes = 0;
+        let mut largest_free = 0;
+        for (level, free_list) in self.free_lists.iter().enumerate() {
+            let level_block_size = self.max_size() >> level;
+            free_bytes += free_list.len() * level_block_size;
+            if !free_list.is_empty() {
+                largest_free = cmp::max(largest_free, level_block_size);
+            }
+        }
+        BuddyStats {
+            start_addr: self.start_addr,
+            end_addr: self.end_addr,
+            allocated_bytes: self.allocated_bytes,
+            peak_bytes: self.peak_bytes,
+            free_bytes,
+            largest_free,
+            allocs: self.allocs,
+            frees: self.frees,
+        }
     }
 
     fn max_size(&self) -> usize {
@@ -302,6 +493,10 @@ impl BuddyAllocator {
             // or we're too full.
             self.get_free_block(req_level).map(|block| {
                 // We got a free block!
+                self.allocated[req_level] += 1;
+                self.allocated_bytes += self.max_size() >> req_level;
+                self.peak_bytes = cmp::max(self.peak_bytes, self.allocated_bytes);
+                self.allocs += 1;
                 // get_free_block gives us the index of the block in the given level
                 // so we need to find the size of each block in that level and multiply by the index
                 // to get the offset of the memory that was allocated.
@@ -323,6 +518,9 @@ impl BuddyAllocator {
             // calculate which # block was just freed by using the start address and block size
             let block_num =
                 ((addr.addr() - self.start_addr.addr()) as usize / level_block_size) as u32;
+            self.allocated[req_level] = self.allocated[req_level].saturating_sub(1);
+            self.allocated_bytes = self.allocated_bytes.saturating_sub(level_block_size);
+            self.frees += 1;
             // push freed block to the free list so we can reuse it
             self.free_lists[req_level].push(block_num);
             // try merging buddy blocks now that we might have some to merge
@@ -384,9 +582,11 @@ impl Display for BuddyAllocator {
             self.block_size,
             (self.block_size as usize) << (self.num_levels as usize),
         );
-        res = res.and_then(|_| write!(f, "  Free lists: "));
+        res = res.and_then(|_| write!(f, "  Free / allocated: "));
         for i in 0usize..(self.num_levels as usize + 1) {
-            res = res.and_then(|_| write!(f, "{} in L{} / ", self.free_lists[i].len(), i));
+            res = res.and_then(|_| {
+                write!(f, "{}/{} in L{} / ", self.free_lists[i].len(), self.allocated[i], i)
+            });
         }
         res
     }
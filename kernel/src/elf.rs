@@ -1,87 +1,221 @@
 use alloc::vec::Vec;
-use alloc::boxed::Box;
-use core::pin::Pin;
-use crate::{mem::VirtAddr, scheduler::Task, mem::PageTable, mem};
-use core::convert::TryInto;
+use crate::{mem::VirtAddr, scheduler::Task, mem::AddressSpace, mem::OutOfMemory, mem};
+use core::cmp::{max, min};
+use core::convert::{TryFrom, TryInto};
 use crate::serial_println;
 
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_HEADER_SIZE: usize = 64;
+const PH_SIZE: usize = 56; // size of a program header, the table's entries can only be larger
+const ET_DYN: u16 = 3; // position independent executable, can be loaded anywhere
+const PT_LOAD: u8 = 1;
+const PT_DYNAMIC: u8 = 2;
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7; // address of the relocation table
+const DT_RELASZ: u64 = 8; // and its size
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8; // load bias + addend, the only kind a static PIE needs
+const PF_X: u32 = 1; // segment is executable
+const PF_W: u32 = 2; // segment is writable (PF_R is implied as x86 can't map unreadable pages)
+
 #[derive(Debug)]
 struct ProgramHeader {
     htype: u8,
+    flags: u32,
     physical_offset: usize,
     load_address: VirtAddr,
-    phys_size: usize,
+    phys_size: usize, // size in the file
+    mem_size: usize,  // size in memory, the rest is zeroed
+}
+
+impl ProgramHeader {
+    fn page_options(&self) -> Result<u64, ElfError> {
+        let writable = self.flags & PF_W != 0;
+        let executable = self.flags & PF_X != 0;
+        if writable && executable && !cfg!(feature = "allow-wx-mappings") {
+            return Err(ElfError::WritableAndExecutable(self.load_address));
+        }
+        let mut options = mem::BIT_PRESENT | mem::BIT_USER;
+        if writable {
+            options |= mem::BIT_WRITABLE;
+        }
+        if !executable {
+            options |= mem::BIT_NO_EXECUTE;
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ElfError {
+    WritableAndExecutable(VirtAddr), // a segment asked to be both writable and executable
+    OutOfMemory,                     // not enough memory for the task's page tables or stack
+    BadSegment(VirtAddr),            // a segment is outside of user space or of the file, or shares a page with one with other flags
+    BadRelocation(usize),            // a relocation of an unsupported type or outside the segments
+    BadHeader,                       // not an ELF file, or its program header table is truncated
+}
+
+impl From<OutOfMemory> for ElfError {
+    fn from(_: OutOfMemory) -> Self {
+        ElfError::OutOfMemory
+    }
 }
 
 pub struct Elf {
-    data: Pin<Box<[u8]>>,
+    data: Vec<u8>, // only needed while loading, the segments are copied out of it
+    elf_type: u16,
     entry_point: VirtAddr,
     headers: Vec<ProgramHeader>,
+    stack_size: usize, // initial size of the task's stack
 }
 
-impl Into<Task> for Elf {
-    fn into(self) -> Task {
-        let mut task_pt = unsafe {PageTable::new()};
-        let phys_addr = unsafe {VirtAddr::new(self.data.as_ptr() as usize).to_phys().unwrap().0};
-        for header in self.headers.iter() {
-            if header.htype != 1 {
+impl TryFrom<Elf> for Task {
+    type Error = ElfError;
+
+    fn try_from(elf: Elf) -> Result<Task, ElfError> {
+        let mut space = unsafe {AddressSpace::try_new()?};
+        // position independent executables are loaded at a random base, the rest where they ask
+        let bias = if elf.elf_type == ET_DYN { mem::random_pie_base() } else { 0 };
+        for header in elf.headers.iter() {
+            if header.htype != PT_LOAD {
                 continue;
             }
-            for page_idx in (0..header.phys_size).step_by(mem::FRAME_SIZE) { // map each page (frame) at a time for this header
+            let options = header.page_options()?; // honor the segment's R/W/X flags
+            let bad_segment = ElfError::BadSegment(header.load_address);
+            let load_start = header.load_address.addr().checked_add(bias).ok_or(bad_segment)?;
+            let load_end = load_start.checked_add(header.mem_size).ok_or(bad_segment)?;
+            let file_end = header.physical_offset.checked_add(header.phys_size).ok_or(bad_segment)?;
+            if load_start < mem::USER_SPACE_START || load_end > mem::USER_SPACE_END || file_end > elf.data.len() {
+                return Err(bad_segment);
+            }
+            // Copy the segment into new frames a page at a time. Whatever is past the segment's
+            // size in the file (ie. .bss) is left zeroed.
+            for page in (load_start / mem::FRAME_SIZE * mem::FRAME_SIZE..load_end).step_by(mem::FRAME_SIZE) {
                 unsafe {
-                    let page_virt = header.load_address.offset(page_idx * mem::FRAME_SIZE);
-                    let page_phys = phys_addr.offset(header.physical_offset + page_idx * mem::FRAME_SIZE);
-                    serial_println!("ELF: Mapping {:x} to {:x}", page_virt.addr(), page_phys.addr());
-                    task_pt.map_virt_to_phys(
-                        page_virt, // map the nth page corresponding to this header's loadable data
-                        page_phys, // locate the physical address of the prog data for this page
-                        mem::BIT_PRESENT | mem::BIT_USER); // mapped page is user accessible and present
+                    // a page shared with another segment keeps the flags it was first mapped with,
+                    // so both have to ask for the same ones (eg. not .text and .data on one page)
+                    if let Some(pte) = space.page_table().get_mapping(VirtAddr::new(page)) {
+                        let writable = pte.get_bit(mem::BIT_WRITABLE) == (options & mem::BIT_WRITABLE != 0);
+                        let no_exec = pte.get_bit(mem::BIT_NO_EXECUTE) == (options & mem::BIT_NO_EXECUTE != 0);
+                        if !writable || (mem::nx_enabled() && !no_exec) {
+                            return Err(bad_segment);
+                        }
+                    }
+                    let frame = space.map_new_page(VirtAddr::new(page), options)?;
+                    serial_println!("ELF: Mapping {:x} to {:x} (opts {:x})", page, frame.addr(), options);
+                    let copy_start = max(page, load_start);
+                    let copy_end = min(page + mem::FRAME_SIZE, load_start + min(header.phys_size, header.mem_size));
+                    if copy_start < copy_end {
+                        let file_off = header.physical_offset + (copy_start - load_start);
+                        let dst = frame.to_virt().unwrap().offset(copy_start - page);
+                        core::ptr::copy_nonoverlapping(
+                            elf.data[file_off..].as_ptr(),
+                            dst.addr() as *mut u8,
+                            copy_end - copy_start,
+                        );
+                    }
                 }
             }
         }
 
-        let mut stack_space: Pin<Box<[u8]>> = Pin::new(Box::new([0u8; mem::FRAME_SIZE])); // allocate some memory to use for the stack
-        unsafe {
-            let stack_space_phys = VirtAddr::new(stack_space.as_mut_ptr() as *const u8 as usize)
-                .to_phys()
-                .unwrap()
-                .0;
-            // take physical address of stack
-            task_pt.map_virt_to_phys(
-                mem::VirtAddr::new(0x800000),
-                stack_space_phys,
-                mem::BIT_PRESENT | mem::BIT_WRITABLE | mem::BIT_USER,
-            ); // map the stack memory to 0x800000
+        if bias != 0 {
+            unsafe { elf.relocate(&mut space, bias)? };
         }
-        Task::new(
-            self.entry_point,
-            mem::VirtAddr::new(0x801000),
-            task_pt,
-            self.data,
-            stack_space,
-        )
+
+        let stack_end = unsafe { space.map_stack(elf.stack_size, mem::DEFAULT_STACK_LIMIT)? };
+        let entry_point = VirtAddr::new(elf.entry_point.addr().wrapping_add(bias));
+        Ok(Task::new(entry_point, stack_end, space)?)
     }
 }
 
 impl Elf {
-    pub fn new(data: Vec<u8>) -> Self {
+    pub fn new(data: Vec<u8>) -> Result<Self, ElfError> {
+        // any user can spawn any file, so the headers are checked before being used
+        if data.len() < ELF_HEADER_SIZE || &data[..4] != ELF_MAGIC {
+            return Err(ElfError::BadHeader);
+        }
+        let elf_type = u16::from_le_bytes(data[16..18].try_into().unwrap());
         let entry_point = VirtAddr::new(usize::from_le_bytes(data[24..32].try_into().unwrap()));
         let ph_off = usize::from_le_bytes(data[32..40].try_into().unwrap());
         let ph_siz = u16::from_le_bytes(data[54..56].try_into().unwrap()) as usize;
         let ph_cnt = u16::from_le_bytes(data[56..58].try_into().unwrap()) as usize;
+        if ph_siz < PH_SIZE {
+            return Err(ElfError::BadHeader);
+        }
+        let table_end = ph_cnt.checked_mul(ph_siz).and_then(|size| size.checked_add(ph_off));
+        let table = table_end.and_then(|end| data.get(ph_off..end)).ok_or(ElfError::BadHeader)?;
 
-        let headers = (0..ph_cnt).map(|i| {
-            let header_index = ph_off + i * ph_siz;
-            let header = &data[header_index..header_index+ph_siz];
-            let htype = header[0] as u8;
+        let mut headers = Vec::new();
+        headers.try_reserve_exact(ph_cnt).map_err(|_| ElfError::OutOfMemory)?;
+        headers.extend(table.chunks_exact(ph_siz).map(|header| {
+            let htype = header[0];
+            let flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
             let physical_offset = usize::from_le_bytes(header[8..16].try_into().unwrap());
             let load_address = VirtAddr::new(usize::from_le_bytes(header[16..24].try_into().unwrap()));
             let phys_size = usize::from_le_bytes(header[32..40].try_into().unwrap());
-            ProgramHeader { htype, physical_offset, load_address, phys_size }
-        }).collect();
+            let mem_size = usize::from_le_bytes(header[40..48].try_into().unwrap());
+            ProgramHeader { htype, flags, physical_offset, load_address, phys_size, mem_size }
+        }));
 
         serial_println!("Elf headers: {:x?} EIP: {:x?}", headers, entry_point);
 
-        Self { data: Pin::new(data.into_boxed_slice()), entry_point, headers }
+        Ok(Self { data, elf_type, entry_point, headers, stack_size: mem::DEFAULT_STACK_SIZE })
+    }
+
+    fn file_offset(&self, vaddr: usize) -> Option<usize> {
+        // where the (unbiased) address is in the file
+        self.headers
+            .iter()
+            .find(|h| h.htype == PT_LOAD && h.load_address.addr() <= vaddr && vaddr - h.load_address.addr() < h.phys_size)
+            .and_then(|h| h.physical_offset.checked_add(vaddr - h.load_address.addr()))
+    }
+
+    fn read_u64(&self, off: usize) -> Option<u64> {
+        Some(u64::from_le_bytes(self.data.get(off..off.checked_add(8)?)?.try_into().unwrap()))
+    }
+
+    unsafe fn relocate(&self, space: &mut AddressSpace, bias: usize) -> Result<(), ElfError> {
+        // apply the relative relocations of the dynamic section to the loaded segments
+        let dynamic = match self.headers.iter().find(|h| h.htype == PT_DYNAMIC) {
+            Some(dynamic) => dynamic,
+            None => return Ok(()),
+        };
+        let (mut rela, mut rela_size) = (0, 0);
+        for off in (dynamic.physical_offset..dynamic.physical_offset.saturating_add(dynamic.phys_size)).step_by(16) {
+            let bad_dynamic = ElfError::BadSegment(dynamic.load_address);
+            match (self.read_u64(off).ok_or(bad_dynamic)?, self.read_u64(off.saturating_add(8)).ok_or(bad_dynamic)?) {
+                (DT_NULL, _) => break,
+                (DT_RELA, val) => rela = val as usize,
+                (DT_RELASZ, val) => rela_size = val as usize,
+                _ => {}
+            }
+        }
+        if rela_size == 0 {
+            return Ok(());
+        }
+        let table = self.file_offset(rela).ok_or(ElfError::BadRelocation(rela))?;
+        for off in (table..table.saturating_add(rela_size)).step_by(24) {
+            let bad_reloc = ElfError::BadRelocation(off);
+            let target = (self.read_u64(off).ok_or(bad_reloc)? as usize).wrapping_add(bias);
+            let rtype = self.read_u64(off.saturating_add(8)).ok_or(bad_reloc)? as u32;
+            let addend = self.read_u64(off.saturating_add(16)).ok_or(bad_reloc)?;
+            match rtype {
+                R_X86_64_NONE => continue,
+                R_X86_64_RELATIVE if target.is_multiple_of(8) => {}
+                _ => return Err(ElfError::BadRelocation(target)),
+            }
+            // write through the physical memory map, the page might not be writable for the task
+            let pte = space.page_table().get_mapping(VirtAddr::new(target)).ok_or(ElfError::BadRelocation(target))?;
+            let dst = pte.phys_addr().to_virt().unwrap().offset(target % mem::FRAME_SIZE);
+            *(dst.addr() as *mut u64) = addend.wrapping_add(bias as u64);
+        }
+        serial_println!("ELF: Relocated to {:x} ({} relocations)", bias, rela_size / 24);
+        Ok(())
+    }
+
+    pub fn with_stack_size(mut self, stack_size: usize) -> Self {
+        // how much stack to map up front, it can still grow up to mem::DEFAULT_STACK_LIMIT
+        self.stack_size = stack_size;
+        self
     }
 }
\ No newline at end of file
@@ -1,13 +1,33 @@
 use core::fmt::{self, Display, Debug};
 use core::convert::TryInto;
 use alloc::vec::Vec;
-use crate::{port::Port, println};
+use alloc::collections::TryReserveError;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{port::Port, println, serial_println};
+use crate::scheduler::{self, WaitQueue};
 const SECTOR_SIZE: usize = 512;
 pub const DIR_ENTRY_SIZE: usize = 32;
 
 pub struct SizedString<const N: usize>([u8; N]);
 
 static IDE: IDE = IDE::new_primary_master();
+static IDE_BUSY: AtomicBool = AtomicBool::new(false); // a task is using the disk
+static IDE_FREE: WaitQueue = WaitQueue::new(); // woken when the disk isn't busy anymore
+static IDE_IRQ: WaitQueue = WaitQueue::new(); // woken by the disk's interrupt when a sector can be read
+
+fn wait_until(queue: &WaitQueue, mut cond: impl FnMut() -> bool) {
+    // sleep until cond holds if we're a task that can block, else poll it
+    if scheduler::can_block() {
+        queue.wait_event(|| if cond() { Some(()) } else { None });
+    } else {
+        while !cond() {}
+    }
+}
+
+pub fn ide_interrupt() {
+    serial_println!(" # IDE IRQ");
+    IDE_IRQ.wake_all();
+}
 
 impl<const N: usize> SizedString<N> {
     pub fn new(c: &[u8]) -> Self {
@@ -75,7 +95,7 @@ impl IDE {
 
 
         for i in 0..cnt {
-            while !self.is_ready() {} //wait for disk to be ready to transfer stuff
+            wait_until(&IDE_IRQ, || self.is_ready()); //wait for disk to be ready to transfer stuff
 
             for j in 0..SECTOR_SIZE/2 {
                 let b = self.io_port.read(); // read 2 bytes of data
@@ -99,7 +119,11 @@ impl IDE {
         let read_sectors = v.len() / SECTOR_SIZE;
         let start_address = address % SECTOR_SIZE;
 
+        // one command at a time, the others wait for it to finish
+        wait_until(&IDE_FREE, || IDE_BUSY.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok());
         self.read_sectors(first_sector, read_sectors, &mut v);
+        IDE_BUSY.store(false, Ordering::Release);
+        IDE_FREE.wake_all();
 
         buf.copy_from_slice(&v[start_address..(start_address+buf.len())]);
     }
@@ -170,9 +194,10 @@ impl FAT16 {
         self.cluster_sectors as usize * self.sector_size as usize
     }
 
-    pub fn read_data(&self, d: &DirEntry) -> Vec<u8> {
+    pub fn read_data(&self, d: &DirEntry) -> Result<Vec<u8>, TryReserveError> {
         let mut buf = Vec::new();
         let mut to_read = d.size as usize;
+        buf.try_reserve_exact(to_read)?; // the size comes from the disk, it might not fit in memory
         buf.resize(to_read, 0);
         let mut idx = 0usize;
         let cluster_bytes = self.cluster_bytes();
@@ -190,7 +215,33 @@ impl FAT16 {
             cluster = self.next_cluster(cl);
         }
 
-        buf
+        Ok(buf)
+    }
+
+    pub fn read_at(&self, d: &DirEntry, offset: usize, buf: &mut [u8]) -> usize {
+        // read part of a file starting at offset, returns how many bytes were read
+        if offset >= d.size {
+            return 0;
+        }
+        let to_read = buf.len().min(d.size - offset);
+        let cluster_bytes = self.cluster_bytes();
+        let mut cluster = d.cluster;
+        for _ in 0..offset / cluster_bytes {
+            cluster = cluster.and_then(|cl| self.next_cluster(cl)); // skip to the cluster with offset
+        }
+        let mut cluster_off = offset % cluster_bytes;
+        let mut idx = 0usize;
+        while let Some(cl) = cluster {
+            let len = (cluster_bytes - cluster_off).min(to_read - idx);
+            IDE.read(self.cluster_addr(cl) + cluster_off, &mut buf[idx..idx + len]);
+            idx += len;
+            if idx == to_read {
+                break;
+            }
+            cluster_off = 0;
+            cluster = self.next_cluster(cl);
+        }
+        idx
     }
 
     pub fn at(&self, index: u16) -> Option<DirEntry> {
@@ -278,12 +329,10 @@ pub fn load_main() -> Option<Vec<u8>> {
     // println!("FAT16: {:x?}", f);
     for i in f.ls(&f.root()) {
         println!("{:?}", i);
-        if i.is_archive() {
-            let v = f.read_data(&i);
+        if i.is_archive() && &i.name.0[0..4] == "BOOT".as_bytes() {
+            let v = f.read_data(&i).ok()?;
             // println!("contents {:?}", v.iter().take(20).collect::<Vec<&u8>>());
-            if &i.name.0[0..4] == "BOOT".as_bytes() {
-                return Some(v);
-            }
+            return Some(v);
         }
     }
     None
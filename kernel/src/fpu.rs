@@ -0,0 +1,107 @@
+use crate::mem;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Floating point, SSE and AVX state of the tasks. The kernel itself is built without SSE (see the
+// target's features), so interrupt handlers and syscalls never touch these registers and the
+// state only has to be saved and restored when switching tasks. That's done eagerly on every
+// switch, with XSAVE where the CPU has it and FXSAVE otherwise.
+
+const CR0_MP: u64 = 1 << 1; // wait / fwait honour TS
+const CR0_EM: u64 = 1 << 2; // no x87, everything traps
+const CR0_TS: u64 = 1 << 3; // task switched, the next FPU instruction traps
+const CR4_OSFXSR: u64 = 1 << 9; // FXSAVE / FXRSTOR and SSE
+const CR4_OSXMMEXCPT: u64 = 1 << 10; // SIMD exceptions are raised as #XM
+const CR4_OSXSAVE: u64 = 1 << 18; // XSAVE and XCR0
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+const FXSAVE_SIZE: usize = 512;
+const AREA_ALIGN: usize = 64; // XSAVE needs 64, FXSAVE 16
+const DEFAULT_FCW: u16 = 0x37F; // all x87 exceptions masked, double extended precision
+const DEFAULT_MXCSR: u32 = 0x1F80; // all SSE exceptions masked, round to nearest
+
+static XSAVE: AtomicBool = AtomicBool::new(false);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// # Safety
+/// Only once per CPU at boot, before any task runs there.
+pub unsafe fn init_cpu() -> bool {
+    // let user space use the FPU, SSE and AVX on this CPU, returns whether XSAVE is used
+    let features = core::arch::x86_64::__cpuid(1);
+    let xsave = features.ecx & (1 << 26) != 0;
+    let avx = features.ecx & (1 << 28) != 0;
+    let mut cr0: u64;
+    asm!("mov {}, cr0", out(reg) cr0);
+    cr0 = (cr0 | CR0_MP) & !(CR0_EM | CR0_TS);
+    asm!("mov cr0, {}", in(reg) cr0);
+    let mut cr4: u64;
+    asm!("mov {}, cr4", out(reg) cr4);
+    cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+    if xsave {
+        cr4 |= CR4_OSXSAVE;
+    }
+    asm!("mov cr4, {}", in(reg) cr4);
+    if xsave {
+        let xcr0 = XCR0_X87 | XCR0_SSE | if avx { XCR0_AVX } else { 0 };
+        asm!("xsetbv", in("ecx") 0, in("eax") xcr0 as u32, in("edx") (xcr0 >> 32) as u32);
+        let size = core::arch::x86_64::__cpuid_count(0xD, 0).ebx as usize; // for what's in XCR0
+        AREA_SIZE.store(size.max(FXSAVE_SIZE), Ordering::SeqCst);
+    }
+    asm!("fninit");
+    XSAVE.store(xsave, Ordering::SeqCst);
+    xsave
+}
+
+pub fn area_size() -> usize {
+    AREA_SIZE.load(Ordering::Relaxed)
+}
+
+pub struct FpuState(Vec<u8>); // the save area is at the first aligned address in it
+
+impl FpuState {
+    pub fn try_new() -> Result<FpuState, mem::OutOfMemory> {
+        // the state a task starts with: empty x87 stack, zeroed registers, exceptions masked (with
+        // XSAVE everything that's not in the header's bitmap gets its initial value anyway)
+        let len = area_size() + AREA_ALIGN;
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(len).map_err(|_| mem::OutOfMemory)?;
+        buf.resize(len, 0);
+        let state = FpuState(buf);
+        unsafe {
+            let area = state.area();
+            (area as *mut u16).write(DEFAULT_FCW);
+            (area.add(24) as *mut u32).write(DEFAULT_MXCSR);
+        }
+        Ok(state)
+    }
+
+    fn area(&self) -> *mut u8 {
+        let addr = self.0.as_ptr() as usize;
+        ((addr + AREA_ALIGN - 1) & !(AREA_ALIGN - 1)) as *mut u8
+    }
+
+    /// # Safety
+    /// Only when switching away from the task this area belongs to.
+    pub unsafe fn save(&mut self) {
+        // the registers of the task that was running on this CPU
+        if XSAVE.load(Ordering::Relaxed) {
+            asm!("xsave64 [{}]", in(reg) self.area(), in("eax") u32::MAX, in("edx") u32::MAX);
+        } else {
+            asm!("fxsave64 [{}]", in(reg) self.area());
+        }
+    }
+
+    /// # Safety
+    /// Only when switching to the task this area belongs to, once it was initialized or saved.
+    pub unsafe fn restore(&self) {
+        if XSAVE.load(Ordering::Relaxed) {
+            asm!("xrstor64 [{}]", in(reg) self.area(), in("eax") u32::MAX, in("edx") u32::MAX);
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) self.area());
+        }
+    }
+}
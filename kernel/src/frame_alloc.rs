@@ -1,18 +1,64 @@
-use crate::mem::{PhysAddr, VirtAddr, FRAME_SIZE};
+use crate::mem::{PhysAddr, VirtAddr, FRAME_SIZE, KERNEL_OFFSET};
 use crate::serial_println;
-use core::cmp::max;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
 use core::slice::Iter;
 use multiboot2::BootInformation;
-use multiboot2::MemoryArea;
+use multiboot2::{MemoryArea, MemoryAreaType};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 
 pub static mut BOOTINFO_ALLOCATOR: Option<SimpleAllocator> = None;
+// takes over from BOOTINFO_ALLOCATOR once the heap is up, all frames come from here afterwards
+// (only locked with interrupts off, page faults and syscalls take it with the scheduler's locks held)
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
 
 pub trait FrameSingleAllocator: Send {
     unsafe fn allocate(&mut self) -> Option<PhysAddr>;
+    /// # Safety
+    /// The frame must have come from this allocator and not be used anymore.
+    unsafe fn free(&mut self, _frame: PhysAddr) {} // allocators that never reclaim frames ignore this
+}
+
+const LOW_MEMORY_END: usize = 0x100000; // the first MiB holds BIOS data, the EBDA and ACPI tables
+
+pub fn reserved_ranges(boot_info: &'static BootInformation<'static>) -> impl Iterator<Item = (usize, usize)> {
+    // Physical memory ranges that are in use since boot and must never be handed out, even if
+    // the memory map says they're available: the first MiB, the kernel image, the multiboot
+    // information structure and the modules loaded by GRUB.
+    let (kernel_start, kernel_end) = boot_info
+        .elf_sections_tag()
+        .expect("Must have ELF sections tag")
+        .sections()
+        .filter(|section| section.is_allocated())
+        .fold((usize::MAX, 0), |(start, end), section| {
+            // sections are linked in the higher half, the kernel is loaded at their physical address
+            let section_start = section.start_address() as usize - KERNEL_OFFSET;
+            let section_end = section.end_address() as usize - KERNEL_OFFSET;
+            (min(start, section_start), max(end, section_end))
+        });
+    let boot_info_start = unsafe { VirtAddr::new(boot_info.start_address()).to_phys().unwrap().0.addr() };
+    let boot_info_end = boot_info_start + boot_info.total_size();
+    IntoIterator::into_iter([
+        (0, LOW_MEMORY_END),
+        (kernel_start, kernel_end),
+        (boot_info_start, boot_info_end),
+    ])
+    .chain(
+        boot_info
+            .module_tags()
+            .map(|module| (module.start_address() as usize, module.end_address() as usize)),
+    )
+}
+
+fn is_available(mem_area: &MemoryArea) -> bool {
+    // anything else is reserved, ACPI tables (reclaimable or not), hibernation data, bad RAM etc.
+    MemoryAreaType::from(mem_area.typ()) == MemoryAreaType::Available
 }
 
 pub struct SimpleAllocator {
-    kernel_end_phys: usize, // end address of our kernel sections (don't write before this!)
+    boot_info: &'static BootInformation<'static>, // to find the memory that's in use since boot
     mem_areas: Iter<'static, MemoryArea>, // memory areas from multiboot
     cur_area: Option<(usize, usize)>, // currently used area's bounds
     next_page: Option<usize>,     // physical address of last page returned
@@ -22,13 +68,11 @@ unsafe impl core::marker::Send for SimpleAllocator {} // shh it's ok pointers ar
 
 impl SimpleAllocator {
     pub unsafe fn init(boot_info: &'static BootInformation<'static>) {
-        let kernel_end = boot_info.end_address();
-        let kernel_end_phys = VirtAddr::new(kernel_end).to_phys().unwrap().0.addr();
         let mem_tag = boot_info
             .memory_map_tag()
             .expect("Must have memory map tag");
         let mut alloc = SimpleAllocator {
-            kernel_end_phys,
+            boot_info,
             mem_areas: mem_tag.memory_areas().iter(),
             cur_area: None,
             next_page: None,
@@ -38,18 +82,38 @@ impl SimpleAllocator {
         BOOTINFO_ALLOCATOR.replace(alloc);
     }
 
+    fn area_bounds(mem_area: &MemoryArea) -> (usize, usize) {
+        // get base addr and length for current area
+        let base_addr = mem_area.start_address() as usize;
+        let area_len = mem_area.size() as usize;
+        let mem_end = base_addr + area_len;
+        // memory start addr aligned with page size
+        let start_addr = base_addr.div_ceil(FRAME_SIZE) * FRAME_SIZE;
+        // memory end addr aligned with page size
+        let end_addr = max((mem_end / FRAME_SIZE) * FRAME_SIZE, start_addr);
+        (start_addr, end_addr)
+    }
+
+    pub fn remaining_ranges(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        // the available physical memory ranges that haven't been handed out yet
+        // (including the reserved ranges in them)
+        let cur = match (self.next_page, self.cur_area) {
+            (Some(next_page), Some((_, end_addr))) if next_page < end_addr => Some((next_page, end_addr)),
+            _ => None,
+        };
+        cur.into_iter().chain(
+            self.mem_areas
+                .clone()
+                .filter(|mem_area| is_available(mem_area))
+                .map(Self::area_bounds),
+        )
+    }
+
     fn next_area(&mut self) -> Option<(usize, usize)> {
-        self.cur_area = self.mem_areas.next().map(|mem_area| {
-            // get base addr and length for current area
-            let base_addr = mem_area.start_address() as usize;
-            let area_len = mem_area.size() as usize;
-            // start after kernel end
-            let mem_start = max(base_addr, self.kernel_end_phys);
-            let mem_end = base_addr + area_len;
-            // memory start addr aligned with page size
-            let start_addr = ((mem_start + FRAME_SIZE - 1) / FRAME_SIZE) * FRAME_SIZE;
-            // memory end addr aligned with page size
-            let end_addr = (mem_end / FRAME_SIZE) * FRAME_SIZE;
+        // skip the areas that aren't available RAM
+        let mem_area = self.mem_areas.find(|mem_area| is_available(mem_area));
+        self.cur_area = mem_area.map(|mem_area| {
+            let (start_addr, end_addr) = Self::area_bounds(mem_area);
             serial_println!(
                 "- FrameAlloc: New area: {:x} to {:x} ({})",
                 start_addr,
@@ -66,18 +130,218 @@ impl SimpleAllocator {
 
 impl FrameSingleAllocator for SimpleAllocator {
     unsafe fn allocate(&mut self) -> Option<PhysAddr> {
-        // return a page from this area
-        let frame = PhysAddr::new(self.next_page?);
-        // get current area end addr if we still have an area left
-        let (_, end_addr) = self.cur_area?;
-        // increment addr to the next page
-        *(self.next_page.as_mut()?) += FRAME_SIZE;
-        if self.next_page? <= end_addr {
-            Some(frame)
+        loop {
+            // return a page from this area
+            let frame = self.next_page?;
+            // get current area end addr if we still have an area left
+            let (_, end_addr) = self.cur_area?;
+            if frame + FRAME_SIZE > end_addr {
+                // end of the frame is beyond the area limits, go to next area and try again
+                self.next_area()?;
+                continue;
+            }
+            // increment addr to the next page
+            self.next_page = Some(frame + FRAME_SIZE);
+            // skip over anything that is in use since boot
+            let reserved_end = reserved_ranges(self.boot_info)
+                .filter(|(start, end)| frame < *end && frame + FRAME_SIZE > *start)
+                .map(|(_, end)| end)
+                .max();
+            match reserved_end {
+                Some(end) => self.next_page = Some(end.div_ceil(FRAME_SIZE) * FRAME_SIZE),
+                None => return Some(PhysAddr::new(frame)),
+            }
+        }
+    }
+}
+
+pub struct BitmapFrameAllocator {
+    frames: usize,                  // number of frames tracked, from physical address 0
+    bitmap: Vec<u64>,               // a set bit means the frame is in use (or doesn't exist)
+    extra_refs: BTreeMap<usize, usize>, // frame number -> references besides the first one
+    next_free: usize,               // frame number to start looking for a free frame from
+    free_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// # Safety
+    /// Only once at boot, bootinfo_alloc must not hand out frames afterwards.
+    pub unsafe fn init(boot_info: &'static BootInformation<'static>, bootinfo_alloc: &SimpleAllocator) {
+        // Build the bitmap from the memory map: only available memory that the boot allocator
+        // hasn't handed out yet, except for the reserved ranges, is free.
+        let mem_tag = boot_info
+            .memory_map_tag()
+            .expect("Must have memory map tag");
+        let available = || mem_tag.memory_areas().iter().filter(|area| is_available(area));
+        let mem_end = available().map(|area| area.end_address() as usize).max().unwrap_or(0);
+        let frames = mem_end / FRAME_SIZE;
+        let mut bitmap = Vec::with_capacity(frames.div_ceil(64));
+        bitmap.resize(frames.div_ceil(64), !0u64);
+        let mut alloc = BitmapFrameAllocator {
+            frames,
+            bitmap,
+            extra_refs: BTreeMap::new(),
+            next_free: 0,
+            free_frames: 0,
+        };
+        for (start, end) in bootinfo_alloc.remaining_ranges() {
+            for area in available() {
+                // only the part of the remaining range that is inside an available area
+                let area_start = max(start, area.start_address() as usize);
+                let area_end = min(end, area.end_address() as usize);
+                let first = area_start.div_ceil(FRAME_SIZE);
+                let last = area_end / FRAME_SIZE;
+                for frame in first..last {
+                    alloc.set_free(frame);
+                }
+            }
+        }
+        for (start, end) in reserved_ranges(boot_info) {
+            alloc.reserve(start, end);
+        }
+        serial_println!(
+            "- FrameAlloc: {} frames tracked, {} free",
+            alloc.frames,
+            alloc.free_frames
+        );
+        without_interrupts(|| FRAME_ALLOCATOR.lock().replace(alloc));
+    }
+
+    fn is_free(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) == 0
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        if !self.is_free(frame) {
+            self.bitmap[frame / 64] &= !(1 << (frame % 64));
+            self.free_frames += 1;
+        }
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        if self.is_free(frame) {
+            self.bitmap[frame / 64] |= 1 << (frame % 64);
+            self.free_frames -= 1;
+        }
+    }
+
+    fn reserve(&mut self, start: usize, end: usize) {
+        // mark a physical memory range (not necessarily page aligned) as in use forever
+        for frame in (start / FRAME_SIZE)..min(end.div_ceil(FRAME_SIZE), self.frames) {
+            self.set_used(frame);
+        }
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysAddr> {
+        // find `count` free frames in a row, the first one aligned to `align` bytes
+        let align_frames = max(align / FRAME_SIZE, 1);
+        let mut start = self.next_free.div_ceil(align_frames) * align_frames;
+        let mut wrapped = false;
+        loop {
+            if start + count > self.frames {
+                if wrapped || self.next_free == 0 {
+                    return None;
+                }
+                // look again from the start in case something before next_free was freed
+                wrapped = true;
+                start = 0;
+                continue;
+            }
+            match (start..start + count).find(|frame| !self.is_free(*frame)) {
+                Some(used) => {
+                    // skip past the used frame to the next aligned one
+                    start = (used + align_frames) / align_frames * align_frames;
+                }
+                None => break,
+            }
+        }
+        for frame in start..start + count {
+            self.set_used(frame);
+        }
+        if count == 1 || start == self.next_free {
+            self.next_free = start + count;
+        }
+        Some(PhysAddr::new(start * FRAME_SIZE))
+    }
+
+    pub fn add_ref(&mut self, frame: PhysAddr) {
+        // one more page table (or anything else) points to this frame, it's only freed once all
+        // of the references are dropped
+        let frame = frame.addr() / FRAME_SIZE;
+        if frame < self.frames && !self.is_free(frame) {
+            *self.extra_refs.entry(frame).or_insert(0) += 1;
+        }
+    }
+
+    pub fn refcount(&self, frame: PhysAddr) -> usize {
+        let frame = frame.addr() / FRAME_SIZE;
+        if frame >= self.frames || self.is_free(frame) {
+            0
         } else {
-            // end of the frame is beyond the area limits, go to next area and try again
-            self.next_area()?;
-            self.allocate()
+            1 + self.extra_refs.get(&frame).copied().unwrap_or(0)
+        }
+    }
+
+    pub fn free_frame(&mut self, frame: PhysAddr) {
+        // drop a reference to this frame and free it if that was the last one
+        let frame = frame.addr() / FRAME_SIZE;
+        if frame >= self.frames || self.is_free(frame) {
+            serial_println!("! FrameAlloc: freeing frame {:x} that is not allocated", frame * FRAME_SIZE);
+            return;
         }
+        if let Some(refs) = self.extra_refs.get_mut(&frame) {
+            *refs -= 1;
+            if *refs == 0 {
+                self.extra_refs.remove(&frame);
+            }
+            return;
+        }
+        self.set_free(frame);
+        self.next_free = min(self.next_free, frame);
+    }
+}
+
+impl FrameSingleAllocator for BitmapFrameAllocator {
+    unsafe fn allocate(&mut self) -> Option<PhysAddr> {
+        self.allocate_contiguous(1, FRAME_SIZE)
+    }
+
+    unsafe fn free(&mut self, frame: PhysAddr) {
+        self.free_frame(frame)
     }
 }
+
+pub fn alloc_zeroed_frame() -> Option<PhysAddr> {
+    // a frame for a page table or a user page, zeroed so that it doesn't leak old data
+    let frame = without_interrupts(|| unsafe { FRAME_ALLOCATOR.lock().as_mut()?.allocate() })?;
+    unsafe {
+        let virt = frame.to_virt()?;
+        core::ptr::write_bytes(virt.addr() as *mut u8, 0, FRAME_SIZE);
+    }
+    Some(frame)
+}
+
+pub fn free_frame(frame: PhysAddr) {
+    without_interrupts(|| {
+        if let Some(alloc) = FRAME_ALLOCATOR.lock().as_mut() {
+            alloc.free_frame(frame);
+        }
+    })
+}
+
+pub fn ref_frame(frame: PhysAddr) {
+    without_interrupts(|| {
+        if let Some(alloc) = FRAME_ALLOCATOR.lock().as_mut() {
+            alloc.add_ref(frame);
+        }
+    })
+}
+
+pub fn frame_allocator_ready() -> bool {
+    without_interrupts(|| FRAME_ALLOCATOR.lock().is_some())
+}
+
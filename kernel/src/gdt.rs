@@ -1,5 +1,4 @@
-use crate::println;
-use lazy_static::lazy_static;
+use crate::{println, smp};
 use x86_64::instructions::segmentation::{Segment, CS,DS};
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{
@@ -10,67 +9,70 @@ use x86_64::{PrivilegeLevel, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u8 = 0;
 const STACK_SIZE: usize = 0x2000;
-pub static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-pub static mut PRIV_TSS_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
-        tss.privilege_stack_table[0] = {
-            let stack_start = VirtAddr::from_ptr(unsafe { &PRIV_TSS_STACK });
+// Every CPU has its own GDT (as it points to the CPU's TSS), TSS and stack for double faults. The
+// segments are in the same order in all of them so the selectors are the same too.
+static mut GDT: [GlobalDescriptorTable; smp::MAX_CPUS] = [const { GlobalDescriptorTable::new() }; smp::MAX_CPUS];
+static mut TSS: [TaskStateSegment; smp::MAX_CPUS] = [const { TaskStateSegment::new() }; smp::MAX_CPUS];
+static mut STACKS: [[u8; STACK_SIZE]; smp::MAX_CPUS] = [[0; STACK_SIZE]; smp::MAX_CPUS];
+static mut SELECTORS: [SegmentSelector; 5] = [SegmentSelector(0); 5]; // kernel code, data, TSS, user data, code
+
+pub fn init_gdt(cpu: usize) {
+    unsafe {
+        TSS[cpu].interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            let stack_start = VirtAddr::from_ptr(&STACKS[cpu]);
             let stack_end = stack_start + STACK_SIZE as u64;
             stack_end
         };
-        tss
-    };
-}
-
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, [SegmentSelector; 5]) = {
-        let mut gdt = GlobalDescriptorTable::new();
+        // the privilege stack (RSP0) is set to the kernel stack of each task when it's switched to
+        let gdt = &mut GDT[cpu];
         let kernel_data_flags =
             DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT | DescriptorFlags::WRITABLE;
         let code_sel = gdt.append(Descriptor::kernel_code_segment());
         let data_sel = gdt.append(Descriptor::UserSegment(kernel_data_flags.bits()));
-        let tss_sel = gdt.append(Descriptor::tss_segment(&TSS));
+        let tss_sel = gdt.append(Descriptor::tss_segment(&TSS[cpu]));
         let user_data_sel = gdt.append(Descriptor::user_data_segment());
         let user_code_sel = gdt.append(Descriptor::user_code_segment());
-        (
-            gdt,
-            [code_sel, data_sel, tss_sel, user_data_sel, user_code_sel],
-        )
-    };
-}
-
-pub fn init_gdt() {
-    GDT.0.load();
-    let stack = unsafe { &STACK as *const _ };
-    let user_stack = unsafe { &PRIV_TSS_STACK as *const _ };
-    println!(
-        " - Loaded GDT: {:p} TSS: {:p} Stack {:p} User stack: {:p} CS segment: {} TSS segment: {}",
-        &GDT.0 as *const _, &*TSS as *const _, stack, user_stack, GDT.1[0].0, GDT.1[1].0
-    );
-    unsafe {
-        CS::set_reg(GDT.1[0]);
+        SELECTORS = [code_sel, data_sel, tss_sel, user_data_sel, user_code_sel];
+        GDT[cpu].load();
+        println!(
+            " - Loaded GDT of CPU {}: {:p} TSS: {:p} Stack {:p} CS segment: {} TSS segment: {}",
+            cpu, &GDT[cpu] as *const _, &TSS[cpu] as *const _, &STACKS[cpu] as *const _, code_sel.0, tss_sel.0
+        );
+        CS::set_reg(code_sel);
         // set_cs(GDT.1[0]);
         // load_ds(GDT.1[1]);
-        DS::set_reg(GDT.1[1]);
-        load_tss(GDT.1[2]);
+        DS::set_reg(data_sel);
+        load_tss(tss_sel);
+        smp::init_cpu(cpu); // and its per-CPU data
     }
 }
 
 #[inline(always)]
 pub unsafe fn set_usermode_segs() -> (u16, u16) {
     // set ds and tss, return cs and ds
-    let (mut cs, mut ds) = (GDT.1[4], GDT.1[3]);
+    let (mut cs, mut ds) = (SELECTORS[4], SELECTORS[3]);
     cs.0 |= PrivilegeLevel::Ring3 as u16;
     ds.0 |= PrivilegeLevel::Ring3 as u16;
     // load_ds(ds);
     DS::set_reg(ds);
     (cs.0, ds.0)
 }
+
+pub fn kernel_segs() -> (u16, u16) {
+    // code and stack segments of ring 0
+    unsafe { (SELECTORS[0].0, SELECTORS[1].0) }
+}
+
+pub fn user_segs() -> (u16, u16) {
+    // code and stack segments of ring 3, with the RPL set for iretq
+    let (cs, ds) = unsafe { (SELECTORS[4].0, SELECTORS[3].0) };
+    (cs | PrivilegeLevel::Ring3 as u16, ds | PrivilegeLevel::Ring3 as u16)
+}
+
+pub unsafe fn set_kernel_stack(stack_end: u64) {
+    // stack to switch to on interrupts and syscalls from user mode, on this CPU
+    let cpu = smp::current();
+    TSS[cpu.id].privilege_stack_table[0] = VirtAddr::new(stack_end);
+    cpu.kernel_stack_end = stack_end;
+}
@@ -1,16 +1,26 @@
-use crate::buddy_alloc::BuddyAllocatorManager;
-use crate::frame_alloc::FrameSingleAllocator;
+use crate::buddy_alloc::{BuddyAllocatorManager, BuddyStats};
+use crate::frame_alloc::{FrameSingleAllocator, FRAME_ALLOCATOR};
+#[cfg(feature = "heap-debug")]
+use crate::heap_debug;
+#[cfg(feature = "heap-poison")]
+use crate::heap_poison;
 use crate::mem::{PhysAddr, VirtAddr, FRAME_SIZE};
 use crate::serial_println;
+use crate::slab_alloc::SlabAllocator;
 use alloc::alloc::{GlobalAlloc, Layout};
 use alloc::vec::Vec;
+use core::cmp::max;
 use core::ptr::null_mut;
 use if_chain::if_chain;
 use lazy_static::lazy_static;
 use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts::without_interrupts;
+
+const HEAP_GROW_SIZE: usize = 1 << 24; // the heap grows 16 MiB at a time
 
 struct AllocatorInfo {
     strategy: RwLock<Option<BuddyAllocatorManager>>,
+    slab: RwLock<Option<SlabAllocator>>,
     frame_allocator: Mutex<Option<&'static mut dyn FrameSingleAllocator>>,
     free_frames: Mutex<Option<Vec<PhysAddr>>>,
 }
@@ -18,6 +28,7 @@ struct AllocatorInfo {
 lazy_static! {
     static ref ALLOCATOR_INFO: AllocatorInfo = AllocatorInfo {
         strategy: RwLock::new(None),
+        slab: RwLock::new(None),
         frame_allocator: Mutex::new(None),
         free_frames: Mutex::new(None),
     };
@@ -26,67 +37,150 @@ lazy_static! {
 pub struct Allocator;
 
 unsafe impl GlobalAlloc for Allocator {
+    // Interrupt handlers allocate too (and syscalls run with interrupts on) so keep them off while
+    // in the allocators, or a handler could spin forever on a lock held by the code it interrupted.
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if_chain! {
-            if let Some(ref strategy) = *ALLOCATOR_INFO.strategy.read();
-            then {
+        without_interrupts(|| {
+            #[cfg(feature = "heap-poison")]
+            let ptr = match heap_poison::outer_layout(layout) {
+                Some(outer) => heap_poison::poison_alloc(alloc_block(outer), layout),
+                None => null_mut(),
+            };
+            #[cfg(not(feature = "heap-poison"))]
+            let ptr = alloc_block(layout);
+            #[cfg(feature = "heap-debug")]
+            heap_debug::track_alloc(ptr, layout);
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            #[cfg(feature = "heap-debug")]
+            {
+                if !heap_debug::track_dealloc(ptr, layout) {
+                    return; // freeing it again would corrupt the allocator
+                }
+            }
+            #[cfg(feature = "heap-poison")]
+            heap_poison::poison_dealloc(ptr, layout, dealloc_block);
+            #[cfg(not(feature = "heap-poison"))]
+            dealloc_block(ptr, layout)
+        })
+    }
+}
+
+unsafe fn alloc_block(layout: Layout) -> *mut u8 {
+    if_chain! {
+        if let Some(ref strategy) = *ALLOCATOR_INFO.strategy.read();
+        then {
+            if_chain! {
+                // small objects come from the slab allocator which gets its pages from the buddy one
+                if SlabAllocator::serves(&layout);
+                if let Some(ref slab) = *ALLOCATOR_INFO.slab.read();
+                then {
+                    let ptr = slab.alloc(layout, strategy);
+                    if !ptr.is_null() {
+                        return ptr;
+                    }
+                    // no page for the slab but the buddy allocator might still have a small block
+                }
+            }
+            let ptr = strategy.alloc(layout);
+            if ptr.is_null() && grow_heap(strategy, &layout) {
                 return strategy.alloc(layout);
             }
+            return ptr;
         }
-        if_chain! {
-            // try locking the free_frames mutex (this locking fails when dealloc needs to allocate
-            // more space for its Vec and calls this as it already holds this lock!)
-            if let Some(ref mut guard) = ALLOCATOR_INFO.free_frames.try_lock();
-            // get as mutable
-            if let Some(ref mut free) = guard.as_mut();
-            // get last page (if it exists)
-            if let Some(page) = free.pop();
-            // if a page exists
-            if let Some(virt) = page.to_virt();
-            // return the page
-            then {
-                serial_println!(" - GlobalAlloc: Reusing {:x}", virt.addr());
-                return virt.to_ref();
-            }
+    }
+    if_chain! {
+        // try locking the free_frames mutex (this locking fails when dealloc needs to allocate
+        // more space for its Vec and calls this as it already holds this lock!)
+        if let Some(ref mut guard) = ALLOCATOR_INFO.free_frames.try_lock();
+        // get as mutable
+        if let Some(ref mut free) = guard.as_mut();
+        // get last page (if it exists)
+        if let Some(page) = free.pop();
+        // if a page exists
+        if let Some(virt) = page.to_virt();
+        // return the page
+        then {
+            serial_println!(" - GlobalAlloc: Reusing {:x}", virt.addr());
+            return virt.to_ref();
         }
-        if_chain! {
-            // lock the frame allocator
-            if let Some(ref mut allocator) = ALLOCATOR_INFO.frame_allocator.lock().as_mut();
-            // get a physical page from it
-            if let Some(page) = allocator.allocate();
-            // convert it to virtual (add 0xC0000000)
-            if let Some(virt) = page.to_virt();
-            // return the page
-            then {
-                serial_println!(" - GlobalAlloc: Allocated {:x} {}", virt.addr(), layout.size());
-                return virt.to_ref();
-            }
+    }
+    if_chain! {
+        // lock the frame allocator
+        if let Some(ref mut allocator) = ALLOCATOR_INFO.frame_allocator.lock().as_mut();
+        // get a physical page from it
+        if let Some(page) = allocator.allocate();
+        // convert it to virtual (add 0xC0000000)
+        if let Some(virt) = page.to_virt();
+        // return the page
+        then {
+            serial_println!(" - GlobalAlloc: Allocated {:x} {}", virt.addr(), layout.size());
+            return virt.to_ref();
         }
-        null_mut()
     }
+    null_mut()
+}
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if_chain! {
-            if let Some(ref strategy) = *ALLOCATOR_INFO.strategy.read();
-            then {
-                return strategy.dealloc(ptr, layout);
+unsafe fn grow_heap(strategy: &BuddyAllocatorManager, layout: &Layout) -> bool {
+    // Add a new area to the buddy allocator with memory from the frame allocator. Try smaller
+    // areas (as long as they fit this allocation) if there's no large enough contiguous memory.
+    let needed = max(max(layout.size(), layout.align()), FRAME_SIZE).next_power_of_two();
+    let mut size = max(HEAP_GROW_SIZE, needed);
+    loop {
+        // the frame allocator might be the one allocating, don't wait for it
+        let area = FRAME_ALLOCATOR
+            .try_lock()
+            .and_then(|mut frame_alloc| frame_alloc.as_mut()?.allocate_contiguous(size / FRAME_SIZE, FRAME_SIZE));
+        match area {
+            Some(start) => {
+                serial_println!(" - GlobalAlloc: Growing heap by {:x} bytes at {}", size, start);
+                if strategy.try_add_memory_area(start, start.offset(size), 16) {
+                    return true;
+                }
+                for frame in (0..size).step_by(FRAME_SIZE) {
+                    crate::frame_alloc::free_frame(start.offset(frame));
+                }
+                return false;
             }
+            None if size / 2 >= needed => size /= 2,
+            None => return false,
         }
-        if_chain! {
-            // try converting the deallocated virtual page address to the physical address
-            if let Some((phys_addr, _)) = VirtAddr::new(ptr as usize).to_phys();
-            // try locking the free frames list (this fails if we've already locked free_frames
-            // for some reason, i.e. if we're in the middle of reallocating it due to a push to it)
-            if let Some(ref mut guard) = ALLOCATOR_INFO.free_frames.try_lock();
-            // get as mutable
-            if let Some(ref mut free) = guard.as_mut();
-            // add the physical address to the free frames list
-            then {
-                free.push(phys_addr);
-            }
+    }
+}
+
+unsafe fn dealloc_block(ptr: *mut u8, layout: Layout) {
+    if_chain! {
+        // small blocks always go to the slab allocator, even if they came from the buddy one
+        if SlabAllocator::serves(&layout);
+        if let Some(ref slab) = *ALLOCATOR_INFO.slab.read();
+        then {
+            return slab.dealloc(ptr, layout);
+        }
+    }
+    if_chain! {
+        if let Some(ref strategy) = *ALLOCATOR_INFO.strategy.read();
+        then {
+            return strategy.dealloc(ptr, layout);
+        }
+    }
+    if_chain! {
+        // try converting the deallocated virtual page address to the physical address
+        if let Some((phys_addr, _)) = VirtAddr::new(ptr as usize).to_phys();
+        // try locking the free frames list (this fails if we've already locked free_frames
+        // for some reason, i.e. if we're in the middle of reallocating it due to a push to it)
+        if let Some(ref mut guard) = ALLOCATOR_INFO.free_frames.try_lock();
+        // get as mutable
+        if let Some(ref mut free) = guard.as_mut();
+        // add the physical address to the free frames list
+        then {
+            free.push(phys_addr);
         }
-        serial_println!(" - GlobalAlloc: Deallocated {:x}", ptr as usize);
     }
+    serial_println!(" - GlobalAlloc: Deallocated {:x}", ptr as usize);
 }
 
 pub fn init_allocator_info(frame_alloc: &'static mut dyn FrameSingleAllocator) {
@@ -131,13 +225,39 @@ pub fn init_global_alloc(frame_alloc: &'static mut dyn FrameSingleAllocator) {
             // Allocate increasingly large memory areas.
             // The previously created buddy allocator (which uses a single page) will be used to back
             // the first of these areas' internal structures to avoid the area having to use itself.
-            // Then the first two areas will be used to support the third, etc.
-            // Until we can support 1GiB buddy allocators (the final type) which need a big
-            // amount of continuous backing memory (some MiB for the is_split bitmap plus
-            // several Vecs for the free lists).
+            // Then the first two areas will be used to support the third.
+            // The rest of the memory is left to the frame allocator, and the heap grows with memory
+            // from it when it runs out (see grow_heap).
             buddy_manager.add_mem_area_with_size(frame_alloc, FRAME_SIZE * 8, 16);
             buddy_manager.add_mem_area_with_size(frame_alloc, FRAME_SIZE * 64, 16);
-            buddy_manager.add_mem_area_with_size(frame_alloc, 1 << 24, 16);
-            while buddy_manager.add_mem_area_with_size(frame_alloc, 1 << 30, 16) {}
+            buddy_manager.add_mem_area_with_size(frame_alloc, HEAP_GROW_SIZE, 16);
         });
+    // Now that the buddy allocator has plenty of memory, serve small allocations from slabs.
+    // This can't happen earlier as the slab allocator takes a whole page for its first object of
+    // each size, which the tiny bootstrap areas above can't afford.
+    ALLOCATOR_INFO.slab.write().replace(SlabAllocator::new());
+}
+
+pub fn slab_stats() -> Option<[crate::slab_alloc::SlabStats; crate::slab_alloc::SIZE_CLASSES.len()]> {
+    ALLOCATOR_INFO.slab.read().as_ref().map(|slab| slab.stats())
+}
+
+pub fn buddy_stats() -> Option<Vec<BuddyStats>> {
+    ALLOCATOR_INFO
+        .strategy
+        .read()
+        .as_ref()
+        .map(|strategy| strategy.stats())
+}
+
+pub fn dump_heap_stats() {
+    // print the state of all the allocators to serial
+    if let Some(ref strategy) = *ALLOCATOR_INFO.strategy.read() {
+        strategy.dump();
+    }
+    if let Some(ref slab) = *ALLOCATOR_INFO.slab.read() {
+        serial_println!("SlabAllocator:\n{}", slab);
+    }
+    #[cfg(feature = "heap-debug")]
+    heap_debug::dump_live_allocs(0);
 }
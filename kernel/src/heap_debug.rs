@@ -0,0 +1,166 @@
+use crate::backtrace::backtrace;
+use crate::serial_println;
+use alloc::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+// Records every live heap allocation with the call stack that made it, in order to find leaks
+// and double frees. Only built with the "heap-debug" feature as it makes every (de)allocation
+// scan a big table.
+
+const MAX_TRACKED: usize = 4096; // live allocations that fit in the table, the rest are not tracked
+const BACKTRACE_LEN: usize = 6; // return addresses kept per allocation
+const RECENT_FREES: usize = 64; // freed pointers remembered to tell double frees from unknown ones
+
+#[derive(Clone, Copy)]
+struct AllocRecord {
+    ptr: usize, // 0 if the slot is empty
+    size: usize,
+    seq: usize, // sequence number of the allocation, to only look at those after some point
+    callers: [usize; BACKTRACE_LEN],
+}
+
+struct HeapTracker {
+    live: [AllocRecord; MAX_TRACKED],
+    live_count: usize,
+    live_bytes: usize,
+    recent_frees: [usize; RECENT_FREES], // ring of the last pointers freed
+    recent_idx: usize,
+    dropped: usize,         // allocations that didn't fit in the table
+    double_frees: usize,    // frees of a pointer that was just freed
+    untracked_frees: usize, // frees of pointers never seen (ie. allocated before tracking started)
+}
+
+const EMPTY_RECORD: AllocRecord = AllocRecord {
+    ptr: 0,
+    size: 0,
+    seq: 0,
+    callers: [0; BACKTRACE_LEN],
+};
+
+static TRACKER: Mutex<HeapTracker> = Mutex::new(HeapTracker {
+    live: [EMPTY_RECORD; MAX_TRACKED],
+    live_count: 0,
+    live_bytes: 0,
+    recent_frees: [0; RECENT_FREES],
+    recent_idx: 0,
+    dropped: 0,
+    double_frees: 0,
+    untracked_frees: 0,
+});
+static NEXT_SEQ: AtomicUsize = AtomicUsize::new(1);
+static MISSED: AtomicUsize = AtomicUsize::new(0); // (de)allocations while the tracker was busy
+
+pub fn alloc_seq() -> usize {
+    // current sequence number, dump_live_allocs(alloc_seq()) later shows what was allocated since
+    NEXT_SEQ.load(Ordering::Relaxed)
+}
+
+pub fn track_alloc(ptr: *mut u8, layout: Layout) {
+    if ptr.is_null() {
+        return;
+    }
+    let callers = backtrace();
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    // an interrupt might allocate while we hold the lock so don't wait for it
+    let mut tracker = match TRACKER.try_lock() {
+        Some(tracker) => tracker,
+        None => {
+            MISSED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    match tracker.live.iter().position(|rec| rec.ptr == 0) {
+        Some(idx) => {
+            tracker.live[idx] = AllocRecord {
+                ptr: ptr as usize,
+                size: layout.size(),
+                seq,
+                callers,
+            };
+            tracker.live_count += 1;
+            tracker.live_bytes += layout.size();
+        }
+        None => tracker.dropped += 1,
+    }
+    // the block was handed out again so it's not a recently freed one anymore
+    for freed in tracker.recent_frees.iter_mut() {
+        if *freed == ptr as usize {
+            *freed = 0;
+        }
+    }
+}
+
+pub fn track_dealloc(ptr: *mut u8, layout: Layout) -> bool {
+    // returns false if the block must not be freed (ie. it's a double free)
+    let mut tracker = match TRACKER.try_lock() {
+        Some(tracker) => tracker,
+        None => {
+            MISSED.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+    };
+    let addr = ptr as usize;
+    if let Some(idx) = tracker.live.iter().position(|rec| rec.ptr == addr) {
+        let rec = tracker.live[idx];
+        if rec.size != layout.size() {
+            serial_println!(
+                "! heap-debug: {:x} freed with size {} but allocated with size {} from {:x?}",
+                addr,
+                layout.size(),
+                rec.size,
+                rec.callers
+            );
+        }
+        tracker.live[idx] = EMPTY_RECORD;
+        tracker.live_count -= 1;
+        tracker.live_bytes -= rec.size;
+        let recent_idx = tracker.recent_idx;
+        tracker.recent_frees[recent_idx] = addr;
+        tracker.recent_idx = (recent_idx + 1) % RECENT_FREES;
+        true
+    } else if tracker.recent_frees.contains(&addr) {
+        tracker.double_frees += 1;
+        drop(tracker);
+        serial_println!(
+            "! heap-debug: double free of {:x} ({} bytes) from {:x?}",
+            addr,
+            layout.size(),
+            backtrace::<BACKTRACE_LEN>()
+        );
+        false
+    } else {
+        tracker.untracked_frees += 1;
+        true
+    }
+}
+
+pub fn dump_live_allocs(since: usize) {
+    // Print every live allocation made after the sequence number `since` (0 for all of them).
+    // Anything listed here that should have been freed by now is a leak.
+    let tracker = TRACKER.lock();
+    serial_println!(
+        "heap-debug: {} live allocations ({} bytes) / {} not tracked / {} missed / {} double frees / {} untracked frees",
+        tracker.live_count,
+        tracker.live_bytes,
+        tracker.dropped,
+        MISSED.load(Ordering::Relaxed),
+        tracker.double_frees,
+        tracker.untracked_frees
+    );
+    for rec in tracker.live.iter().filter(|rec| rec.ptr != 0 && rec.seq >= since) {
+        serial_println!(
+            " #{}: {:x} ({} bytes) from {:x?}",
+            rec.seq,
+            rec.ptr,
+            rec.size,
+            rec.callers
+        );
+    }
+}
+
+pub fn live_allocs() -> (usize, usize) {
+    // number of allocations and bytes currently live
+    let tracker = TRACKER.lock();
+    (tracker.live_count, tracker.live_bytes)
+}
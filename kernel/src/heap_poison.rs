@@ -0,0 +1,153 @@
+use crate::backtrace::backtrace;
+use crate::serial_println;
+use alloc::alloc::Layout;
+use core::cmp;
+use core::ptr::write_bytes;
+use spin::Mutex;
+
+// Kernel heap poisoning, built with the "heap-poison" feature. Every allocation gets a redzone
+// before and after it, which are checked when it's freed to catch overflows. Freed blocks are
+// filled with a poison pattern and kept in a quarantine for a while before they're really freed,
+// and the pattern is checked on the way out to catch writes after the free.
+//
+// Layout of a block: [header | redzone][user data][redzone]
+// where the header keeps the size and the call stack of the allocation for the reports.
+
+const ALLOC_POISON: u8 = 0xCD; // new allocations, so that uninitialized reads stand out
+const FREE_POISON: u8 = 0xDD; // freed (quarantined) memory
+const REDZONE_POISON: u8 = 0xFA;
+const CALLERS: usize = 4; // return addresses of the allocation kept in the header
+const FRONT_REDZONE: usize = 64; // including the header
+const BACK_REDZONE: usize = 32;
+const QUARANTINE_LEN: usize = 512; // max blocks waiting to be freed
+const QUARANTINE_BYTES: usize = 1 << 20; // max bytes waiting to be freed
+
+#[repr(C)]
+struct Header {
+    size: usize,
+    callers: [usize; CALLERS],
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+
+struct Quarantine {
+    blocks: [(usize, usize, usize); QUARANTINE_LEN], // user pointer, size and alignment of each block
+    head: usize,                                     // index of the oldest block
+    count: usize,
+    bytes: usize,
+}
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine {
+    blocks: [(0, 0, 0); QUARANTINE_LEN],
+    head: 0,
+    count: 0,
+    bytes: 0,
+});
+
+fn front_size(layout: &Layout) -> usize {
+    // the user data must stay aligned so the front redzone might have to be larger
+    cmp::max(FRONT_REDZONE, layout.align())
+}
+
+pub fn outer_layout(layout: Layout) -> Option<Layout> {
+    // the layout to actually allocate for this one, with the redzones around it
+    let size = front_size(&layout) + layout.size() + BACK_REDZONE;
+    Layout::from_size_align(size, cmp::max(layout.align(), 8)).ok()
+}
+
+unsafe fn first_bad_byte(start: usize, len: usize, pattern: u8) -> Option<usize> {
+    (0..len).find(|i| *((start + i) as *const u8) != pattern)
+}
+
+unsafe fn report(what: &str, ptr: usize, offset: isize, size: usize) {
+    let header = &*((ptr - FRONT_REDZONE) as *const Header);
+    serial_println!(
+        "! heap-poison: {} at offset {} of {:x} ({} bytes, allocated from {:x?}), detected at {:x?}",
+        what,
+        offset,
+        ptr,
+        size,
+        header.callers,
+        backtrace::<CALLERS>()
+    );
+}
+
+pub unsafe fn poison_alloc(block: *mut u8, layout: Layout) -> *mut u8 {
+    if block.is_null() {
+        return block;
+    }
+    let front = front_size(&layout);
+    let ptr = block.add(front);
+    // the header goes right before the front redzone's pattern so that it's always at a fixed
+    // offset from the user pointer, even if the front redzone is larger for alignment
+    let header = ptr.sub(FRONT_REDZONE) as *mut Header;
+    write_bytes(block, REDZONE_POISON, front);
+    header.write(Header {
+        size: layout.size(),
+        callers: backtrace(),
+    });
+    write_bytes(ptr, ALLOC_POISON, layout.size());
+    write_bytes(ptr.add(layout.size()), REDZONE_POISON, BACK_REDZONE);
+    ptr
+}
+
+pub unsafe fn poison_dealloc(ptr: *mut u8, layout: Layout, free: unsafe fn(*mut u8, Layout)) {
+    let addr = ptr as usize;
+    let size = layout.size();
+    let header = &*((addr - FRONT_REDZONE) as *const Header);
+    if header.size != size {
+        report("free with wrong size", addr, size as isize, header.size);
+    }
+    let front_pattern = addr - FRONT_REDZONE + HEADER_SIZE;
+    if let Some(i) = first_bad_byte(front_pattern, FRONT_REDZONE - HEADER_SIZE, REDZONE_POISON) {
+        report("buffer underflow", addr, (front_pattern + i) as isize - addr as isize, size);
+    }
+    if let Some(i) = first_bad_byte(addr + size, BACK_REDZONE, REDZONE_POISON) {
+        report("buffer overflow", addr, (size + i) as isize, size);
+    }
+    write_bytes(ptr, FREE_POISON, size);
+    // Put the block in the quarantine so that it doesn't get reused for a while, and really free
+    // the oldest blocks when it's full. An interrupt might free while we hold the lock so never
+    // wait for it, just skip the quarantine if it's busy.
+    if QUARANTINE.try_lock().map_or(false, |q| q.count == QUARANTINE_LEN) {
+        evict_oldest(free);
+    }
+    match QUARANTINE.try_lock() {
+        Some(mut quarantine) if quarantine.count < QUARANTINE_LEN => {
+            let idx = (quarantine.head + quarantine.count) % QUARANTINE_LEN;
+            quarantine.blocks[idx] = (addr, size, layout.align());
+            quarantine.count += 1;
+            quarantine.bytes += size;
+        }
+        _ => {
+            free(ptr.sub(front_size(&layout)), outer_layout(layout).unwrap());
+            return;
+        }
+    }
+    while QUARANTINE.try_lock().map_or(false, |q| q.bytes > QUARANTINE_BYTES) {
+        if !evict_oldest(free) {
+            break;
+        }
+    }
+}
+
+unsafe fn evict_oldest(free: unsafe fn(*mut u8, Layout)) -> bool {
+    // take the oldest block out of the quarantine, check that nobody wrote to it and free it
+    let (addr, size, align) = match QUARANTINE.try_lock() {
+        Some(mut quarantine) if quarantine.count > 0 => {
+            let oldest = quarantine.blocks[quarantine.head];
+            quarantine.head = (quarantine.head + 1) % QUARANTINE_LEN;
+            quarantine.count -= 1;
+            quarantine.bytes -= oldest.1;
+            oldest
+        }
+        _ => return false,
+    };
+    // free it after releasing the lock as the allocator might free something itself
+    if let Some(i) = first_bad_byte(addr, size, FREE_POISON) {
+        report("use after free", addr, i as isize, size);
+    }
+    let layout = Layout::from_size_align_unchecked(size, align);
+    free((addr - front_size(&layout)) as *mut u8, outer_layout(layout).unwrap());
+    true
+}
@@ -4,7 +4,8 @@ use alloc::vec::Vec;
 use crate::port::{end_of_interrupt, Port};
 use crate::scheduler;
 use crate::syscalls;
-use crate::{print, println, serial_println};
+use crate::usercopy;
+use crate::{print, println};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -13,6 +14,7 @@ use x86_64::registers::segmentation::Segment;
 use x86_64::registers::segmentation::CS;
 use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
 use x86_64::structures::gdt::SegmentSelector;
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::InterruptStackFrame;
 
 use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
@@ -29,9 +31,32 @@ lazy_static! {
         Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
 }
 
+struct KernelGs(bool);
+
+impl KernelGs {
+    // Switch to this CPU's gs when interrupted in user space, and back when dropped at the end of
+    // the handler. Has to come first in handlers that use per-CPU data (ie. the scheduler).
+    unsafe fn enter(stack_frame: &InterruptStackFrame) -> KernelGs {
+        let from_user = stack_frame.code_segment.0 & 3 == 3;
+        if from_user {
+            asm!("swapgs");
+        }
+        KernelGs(from_user)
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.0 {
+            unsafe { asm!("swapgs") };
+        }
+    }
+}
+
 macro_rules! irq_fn {
     ($f: ident, $i: literal, $e:expr) => {
-        unsafe extern "x86-interrupt" fn $f(_stack_frame: &mut InterruptStackFrame) {
+        unsafe extern "x86-interrupt" fn $f(stack_frame: &mut InterruptStackFrame) {
+            let _gs = KernelGs::enter(stack_frame);
             asm!("cli");
             $e();
             end_of_interrupt($i);
@@ -49,10 +74,32 @@ extern "x86-interrupt" fn breakpoint(stack_frame: &mut InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn page_fault(stack_frame: &mut InterruptStackFrame, err_code: u64) {
-    println!(" !! page fault @ {:x}! err code: {} {:?}", stack_frame.instruction_pointer.as_u64(), err_code, stack_frame);
+    let _gs = unsafe { KernelGs::enter(stack_frame) };
+    let addr: usize;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) addr); // the address that caused the fault
+        let from_user = stack_frame.code_segment.0 & 3 == 3; // faulted in ring 3
+        let write = err_code & 2 != 0;
+        let interrupts_on = stack_frame.cpu_flags.contains(RFlags::INTERRUPT_FLAG); // whether the faulting code could be preempted
+        if scheduler::handle_page_fault(addr, write, from_user, interrupts_on) {
+            return; // the page is there now, retry the access
+        }
+    }
+    if let Some(fixup) = usercopy::fault_fixup(stack_frame.instruction_pointer.as_u64() as usize) {
+        // a copy from / to user memory hit a bad page, make it return an error instead
+        unsafe {
+            stack_frame.as_mut().update(|frame| frame.instruction_pointer = VirtAddr::new(fixup as u64));
+        }
+        return;
+    }
+    println!(" !! page fault @ {:x} accessing {:x}! err code: {} {:?}", stack_frame.instruction_pointer.as_u64(), addr, err_code, stack_frame);
     loop {}
 }
 
+extern "x86-interrupt" fn spurious(_stack_frame: &mut InterruptStackFrame) {
+    // the local APIC sends these when an interrupt went away before it was delivered, no EOI
+}
+
 extern "x86-interrupt" fn gpf(stack_frame: &mut InterruptStackFrame, err_code: u64) {
     println!(" !! gpf! err code: {} {:?}", err_code, stack_frame);
     loop {}
@@ -63,14 +110,18 @@ extern "x86-interrupt" fn double_fault(stack_frame: &mut InterruptStackFrame, er
     loop {}
 }
 
-extern "x86-interrupt" fn ide(_stack_frame: &mut InterruptStackFrame) {
-    serial_println!(" # IDE IRQ");
-}
+irq_fn!(ide, 46, || {
+    crate::fat16::ide_interrupt(); // wakes whoever waits for the disk
+});
 
 // timer interrupt function to change contexts
 #[naked]
 unsafe extern "sysv64" fn timer(_stack_frame: &mut InterruptStackFrame) {
     naked_asm!("\
+    test byte ptr [rsp + 8], 3 // interrupted in user space, switch to this CPU's gs
+    jz 2f
+    swapgs
+    2:
     push r15; push r14; push r13; push r12; push r11; push r10; push r9;\
     push r8; push rdi; push rsi; push rdx; push rcx; push rbx; push rax; push rbp;\
     mov rdi, rsp   // first arg of context switch is the context which is all the registers saved above
@@ -79,6 +130,22 @@ unsafe extern "sysv64" fn timer(_stack_frame: &mut InterruptStackFrame) {
     ", context_switch = sym scheduler::context_switch);
 }
 
+// software interrupt used by the kernel to switch to the next task without waiting for the timer
+#[naked]
+unsafe extern "sysv64" fn yield_task(_stack_frame: &mut InterruptStackFrame) {
+    naked_asm!("\
+    push r15; push r14; push r13; push r12; push r11; push r10; push r9;\
+    push r8; push rdi; push rsi; push rdx; push rcx; push rbx; push rax; push rbp;\
+    mov rdi, rsp   // the saved context, same as the timer
+    sub rsp, 0x400
+    jmp {yield_switch}
+    ", yield_switch = sym scheduler::yield_switch);
+}
+
+irq_fn!(tlb_shootdown, 0xF0, || {
+    crate::smp::ack_shootdown(); // another CPU changed the page table of a task running here
+});
+
 irq_fn!(keyboard, 33, || {
     let port: Port<u8> = Port::new(0x60);
     let scancode = port.read();
@@ -92,6 +159,7 @@ irq_fn!(keyboard, 33, || {
                     chars.clone_into(&mut chars_cp);
                     *syscalls::STDIN_BUF.lock() = Some(chars_cp); // replace the stdin buf with the current recorded keys
                     *chars = Vec::new(); // replace the keys buf with an empty char vec
+                    syscalls::STDIN.wake_all(); // let a waiting getline have it
                 },
                 DecodedKey::Unicode(character) => {
                     print!("{}", character);
@@ -128,6 +196,9 @@ lazy_static! {
         idt_entry!(32, timer);
         idt_entry!(33, keyboard);
         idt_entry!(46, ide);
+        idt_entry!(0x81, yield_task); // scheduler::YIELD_VECTOR
+        idt_entry!(0xF0, tlb_shootdown); // smp::TLB_SHOOTDOWN_VECTOR
+        idt_entry!(0xFF, spurious); // apic::SPURIOUS_VECTOR
         InterruptDescriptorTable(vectors)
     };
 }
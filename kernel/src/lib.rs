@@ -2,7 +2,6 @@
 #![feature(naked_functions)]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
-#![feature(str_from_raw_parts)]
 #![allow(static_mut_refs)]
 
 extern crate alloc;
@@ -10,16 +9,32 @@ extern crate multiboot2;
 extern crate pc_keyboard;
 extern crate x86_64;
 
+pub mod acpi;
+pub mod apic;
+pub mod backtrace;
 pub mod buddy_alloc;
+pub mod fpu;
 pub mod frame_alloc;
 mod gdt;
 pub mod global_alloc;
+#[cfg(feature = "heap-debug")]
+pub mod heap_debug;
+#[cfg(feature = "heap-poison")]
+mod heap_poison;
 pub mod interrupts;
 pub mod mem;
+pub mod mmap;
 pub mod port;
+pub mod random;
+pub mod sched_policy;
 pub mod scheduler;
 pub mod serial_port;
+pub mod shm;
+pub mod slab_alloc;
+pub mod smp;
 pub mod syscalls;
+pub mod time;
+pub mod usercopy;
 pub mod vga_buffer;
 pub mod fat16;
 pub mod elf;
@@ -33,6 +48,7 @@ use crate::port::init_pics;
 use crate::vga_buffer::set_color;
 use crate::vga_buffer::Color;
 use crate::elf::Elf;
+use core::convert::TryInto;
 
 #[cfg(not(feature = "no-panic-handler"))]
 use core::panic::PanicInfo;
@@ -45,6 +61,8 @@ static mut BOOT_INFO: Option<BootInformation> = None;
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    // only reached by infallible kernel allocations, the fallible ones return an error instead
+    global_alloc::dump_heap_stats();
     panic!("allocation error: {:?}", layout)
 }
 
@@ -83,38 +101,100 @@ pub extern "C" fn ua64_mode_start() -> ! {
 
 pub fn start(boot_info: &'static BootInformation) -> ! {
     cls();
-    init_gdt();
+    init_gdt(0); // the BSP is CPU 0
     setup_idt();
+    unsafe {
+        if !mem::enable_nx() {
+            println!(" - CPU has no NX support, user data will be executable");
+        }
+        let (smep, smap) = usercopy::enable_smep_smap();
+        println!(" - SMEP: {} SMAP: {}", smep, smap);
+        let xsave = fpu::init_cpu();
+        println!(" - FPU state: {} bytes with {}", fpu::area_size(), if xsave { "XSAVE" } else { "FXSAVE" });
+    }
     unsafe {
         syscalls::init_syscalls();
     }
+    let rdrand = unsafe { random::init_random() };
+    let cmdline = boot_info.command_line_tag().and_then(|tag| tag.cmdline().ok()).unwrap_or("");
+    if cmdline.split_whitespace().any(|arg| arg == "noaslr") {
+        mem::disable_aslr();
+    }
+    println!(" - ASLR: {} (RDRAND: {})", mem::aslr_enabled(), rdrand);
     unsafe {
         let pt = mem::get_page_table();
         println!("Page table: {:p}", pt);
-        let entry0 = pt.get_entry(0);
-        println!("Entry 0: {}", entry0);
-        let entry03 = entry0.next_pt().get_entry(3);
-        println!("Entry 0-3: {}", entry03);
-        let entry032 = entry03.next_pt().get_entry(2);
-        println!("Entry 0-3-2: {}", entry032);
+        let entry511 = pt.get_entry(511);
+        println!("Entry 511: {}", entry511);
+        let entry511_510 = entry511.next_pt().get_entry(510);
+        println!("Entry 511-510: {}", entry511_510);
+        let entry511_510_0 = entry511_510.next_pt().get_entry(0);
+        println!("Entry 511-510-0: {}", entry511_510_0);
         println!(
-            "addr 0x172d05e00 is: {}",
-            mem::VirtAddr::new(0x172d05e00).to_phys().unwrap().0
+            "start() at {:x} is: {}",
+            start as *const () as usize,
+            mem::VirtAddr::new(start as *const () as usize).to_phys().unwrap().0
         );
     }
     println!("Kernel end at: {:x}", boot_info.end_address());
     unsafe {
         frame_alloc::SimpleAllocator::init(boot_info);
         global_alloc::init_global_alloc(frame_alloc::BOOTINFO_ALLOCATOR.as_mut().unwrap());
+        mem::init_phys_map(boot_info); // page tables come from the heap so this goes after it
+        mem::init_kernel_space();
+        // hand the rest of the memory to the frame allocator, it can only use all of it once
+        // it's mapped in the physical memory map
+        frame_alloc::BitmapFrameAllocator::init(boot_info, frame_alloc::BOOTINFO_ALLOCATOR.as_ref().unwrap());
     }
     set_color(Color::Green, Color::Black, false);
-    init_pics();
+    let timer_hz = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("hz=")?.parse::<u64>().ok())
+        .unwrap_or(time::DEFAULT_TIMER_HZ);
+    let madt = if cmdline.split_whitespace().any(|arg| arg == "noapic") {
+        None
+    } else {
+        unsafe { apic::init(boot_info) } // None without an APIC, the PICs are used then
+    };
+    let apic_timer = madt.as_ref().map(|_| {
+        if cmdline.split_whitespace().any(|arg| arg == "apic_oneshot") {
+            apic::TimerMode::OneShot
+        } else {
+            apic::TimerMode::Periodic
+        }
+    });
+    match &madt {
+        Some(madt) => println!(" - APIC: {} CPUs, {} IO-APICs", madt.cpus.len(), madt.ioapics.len()),
+        None => println!(" - No APIC, using the PICs"),
+    }
+    let tsc_khz = time::init_time(timer_hz, apic_timer); // before interrupts are on, the calibration busy waits
+    println!(" - Timer: {} Hz, TSC: {} kHz", time::timer_hz(), tsc_khz);
+    unsafe { smp::init(madt.as_ref()) }; // also before interrupts, the APs are started with the PIT
+    let policy = cmdline
+        .split_whitespace()
+        .find_map(|arg| sched_policy::PolicyKind::from_name(arg.strip_prefix("sched=")?))
+        .unwrap_or(sched_policy::PolicyKind::Fair);
+    match scheduler::SCHEDULER.set_policy(policy) {
+        Ok(name) => println!(" - Scheduler: {}", name),
+        Err(_) => println!(" - Scheduler: out of memory, some CPUs kept the default"),
+    }
+    init_pics(madt.is_some());
 
     let main = fat16::load_main().unwrap(); // load the /BOOT main program from fat16
 
-    let elf = Elf::new(main); // parse the file as an elf to find loadable sections
-
     let sched = &scheduler::SCHEDULER;
-    sched.schedule_task(elf.into()); // transform to a task and schedule it
-    loop {} // no need to do anything here as we will be interrupted anyway
+    // parse the file as an elf to find loadable sections
+    match Elf::new(main).and_then(|elf| elf.try_into()) {
+        Ok(task) => {
+            // transform to a task and schedule it
+            if sched.schedule_task(task).is_err() {
+                println!("Could not schedule /BOOT: out of memory");
+            }
+        }
+        Err(e) => println!("Could not load /BOOT: {:?}", e),
+    }
+    if sched.spawn_kernel_thread(|| scheduler::log_stats(10_000)).is_err() {
+        println!("Could not start the stats kernel thread");
+    }
+    unsafe { scheduler::enter_idle() } // the BSP runs tasks from now on, or its idle task
 }
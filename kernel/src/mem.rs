@@ -1,10 +1,31 @@
 use core::arch::asm;
-use alloc::boxed::Box;
+use alloc::alloc::{alloc_zeroed, Layout};
+use crate::frame_alloc;
+use crate::mmap::FileMapping;
+use alloc::vec::Vec;
 use core::fmt::Display;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use multiboot2::BootInformation;
 
-const VIRT_OFFSET: usize = 0xC0000000;
+pub const PHYS_MAP_OFFSET: usize = 0xFFFF800000000000; // all physical memory is mapped from here on
+pub const KERNEL_OFFSET: usize = 0xFFFFFFFF80000000; // the kernel image is linked at -2 GiB (+ 1 MiB)
+const KERNEL_SPACE_P4_START: usize = 256; // P4 entries of the higher half, shared between all tasks
+const GIB: usize = 1 << 30;
+const HUGE_PAGE_SIZE: usize = 1 << 21;
 pub const FRAME_SIZE: usize = 0x1000;
-type EmptyFrame = [u8; FRAME_SIZE as usize];
+pub const USER_SPACE_START: usize = FRAME_SIZE; // the null page is never mapped
+// The lower half minus its last page (the kernel lives in the higher one): a syscall right at the
+// end would return to the non-canonical 0x800000000000, where sysretq faults in ring 0 on Intel
+// with the user's stack already loaded.
+pub const USER_SPACE_END: usize = 0x7FFFFFFFF000;
+pub const USER_STACK_TOP: usize = USER_SPACE_END - 0x10000; // user stacks grow down from here (minus a random offset)
+pub const MMAP_BASE: usize = 0x600000000000; // mappings without an address go from here up (plus a random offset)
+pub const PIE_BASE: usize = 0x555500000000; // position independent executables are loaded here (plus a random offset)
+const STACK_RANDOM_PAGES: usize = 1 << 24; // randomize the stack top within 64 GiB
+const MMAP_RANDOM_PAGES: usize = 1 << 28; // and the mmap and PIE bases within 1 TiB
+pub const DEFAULT_STACK_SIZE: usize = 0x4000; // mapped when the task starts
+pub const DEFAULT_STACK_LIMIT: usize = 0x100000; // max size the stack can grow to on faults
+const STACK_OPTIONS: u64 = BIT_PRESENT | BIT_WRITABLE | BIT_USER | BIT_NO_EXECUTE;
 
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -15,39 +36,107 @@ pub struct PageTable {
     entries: [PTEntry; 512],
 }
 
-pub const BIT_PRESENT: u16 = 1;
-pub const BIT_WRITABLE: u16 = 1 << 1;
-pub const BIT_USER: u16 = 1 << 2;
-pub const BIT_WRITE_THROUGH: u16 = 1 << 3;
-pub const BIT_NO_CACHE: u16 = 1 << 4;
-pub const BIT_ACCESSED: u16 = 1 << 5;
-pub const BIT_DIRTY: u16 = 1 << 6;
-pub const BIT_HUGE: u16 = 1 << 7;
-pub const BIT_GLOBAL: u16 = 1 << 8;
+#[derive(Debug, Clone, Copy)]
+pub struct OutOfMemory; // no memory left for a page (table)
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFault {
+    Handled,       // the missing page was mapped (a stack page or a page of a mapped file)
+    StackOverflow, // the fault hit the guard page below the stack's limit
+    OutOfMemory,   // no memory for the new page
+    NeedsPage(u16, usize), // the page (inode, page in the file) of a mapped file has to be read from the disk first
+}
+
+pub const BIT_PRESENT: u64 = 1;
+pub const BIT_WRITABLE: u64 = 1 << 1;
+pub const BIT_USER: u64 = 1 << 2;
+pub const BIT_WRITE_THROUGH: u64 = 1 << 3;
+pub const BIT_NO_CACHE: u64 = 1 << 4;
+pub const BIT_ACCESSED: u64 = 1 << 5;
+pub const BIT_DIRTY: u64 = 1 << 6;
+pub const BIT_HUGE: u64 = 1 << 7;
+pub const BIT_GLOBAL: u64 = 1 << 8;
+pub const BIT_SHARED: u64 = 1 << 9; // ignored by the CPU, marks pages of shared memory objects
+pub const BIT_NO_EXECUTE: u64 = 1 << 63; // only honored by the CPU once EFER.NXE is set
+
+const ADDR_MASK: usize = ((1 << 40) - 1) * FRAME_SIZE; // bits 12-51 hold the physical address
+
+const MSR_EFER: usize = 0xC0000080;
+
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+static PHYS_MAP_END: AtomicUsize = AtomicUsize::new(4 * GIB); // how much physical memory is mapped at PHYS_MAP_OFFSET (boot.asm maps 4 GiB)
+static ASLR_ENABLED: AtomicBool = AtomicBool::new(cfg!(not(feature = "no-aslr")));
+static KERNEL_P4: AtomicUsize = AtomicUsize::new(0); // the boot page table, which has no user space
+
+/// # Safety
+/// Only at boot on each CPU, before anything maps pages with the NX bit.
+pub unsafe fn enable_nx() -> bool {
+    // check the NX bit in the extended processor info before touching EFER
+    let ext_info = core::arch::x86_64::__cpuid(0x80000001);
+    if ext_info.edx & (1 << 20) == 0 {
+        return false;
+    }
+    asm!("\
+    rdmsr
+    or eax, 1 << 11 // set the No-Execute Enable bit
+    wrmsr", in("rcx") MSR_EFER, out("rax") _, out("rdx") _);
+    NX_ENABLED.store(true, Ordering::SeqCst);
+    true
+}
+
+pub fn nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn disable_aslr() {
+    // for deterministic debugging, with "noaslr" on the kernel command line
+    ASLR_ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn aslr_enabled() -> bool {
+    ASLR_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn random_offset(max_pages: usize) -> usize {
+    // a random page aligned offset below max_pages pages, 0 if ASLR is disabled
+    if !aslr_enabled() {
+        return 0;
+    }
+    (crate::random::random() as usize % max_pages) * FRAME_SIZE
+}
+
+pub fn random_pie_base() -> usize {
+    PIE_BASE + random_offset(MMAP_RANDOM_PAGES)
+}
 
 impl PTEntry {
-    pub fn get_bit(&self, bit: u16) -> bool {
+    pub fn get_bit(&self, bit: u64) -> bool {
         (self.0 & (bit as usize)) != 0
     }
 
-    pub fn set_opts(&mut self, options: u16) {
-        let val = (self.0 >> 9) << 9;
+    pub fn set_opts(&mut self, options: u64) {
+        // the NX bit is reserved (and faults) if the CPU doesn't have it enabled
+        let options = if nx_enabled() { options } else { options & !BIT_NO_EXECUTE };
+        let val = ((self.0 >> 9) << 9) & !(BIT_NO_EXECUTE as usize);
         self.0 = val | options as usize;
     }
 
-    pub fn set_bit(&mut self, bit: u16, v: bool) {
+    pub fn set_bit(&mut self, bit: u64, v: bool) {
+        if bit == BIT_NO_EXECUTE && !nx_enabled() {
+            return;
+        }
         if ((self.0 & (bit as usize)) != 0) != v {
             self.0 ^= bit as usize;
         }
     }
 
     pub fn set_phys_addr(&mut self, addr: PhysAddr) {
-        let val = self.0 & ((1 << 9) - 1);
+        let val = self.0 & !ADDR_MASK;
         self.0 = addr.addr() | val;
     }
 
     pub fn phys_addr(&self) -> PhysAddr {
-        PhysAddr::new(self.0 & (((1 << 40) - 1) * FRAME_SIZE))
+        PhysAddr::new(self.0 & ADDR_MASK)
     }
 
     pub unsafe fn next_pt(&self) -> &'static mut PageTable {
@@ -83,6 +172,9 @@ impl Display for PTEntry {
             if self.get_bit(BIT_GLOBAL) {
                 write!(f, " global").unwrap();
             }
+            if self.get_bit(BIT_NO_EXECUTE) {
+                write!(f, " no_execute").unwrap();
+            }
             res
         } else {
             write!(f, "<not present>")
@@ -93,27 +185,300 @@ impl Display for PTEntry {
 pub unsafe fn get_page_table() -> &'static mut PageTable {
     let mut p4: usize;
     asm!("mov rax, cr3", out("rax") p4);
-    &mut *((p4 + VIRT_OFFSET) as *mut PageTable)
+    PhysAddr::new(p4 & ADDR_MASK).to_virt().unwrap().to_ref()
 }
 
-impl PageTable {
-    pub unsafe fn new() -> Box<PageTable> {
-        let mut pt = Box::new(PageTable {
-            entries: [PTEntry(0); 512],
-        }); // allocate the master PT struct
-        pt.entries[0].set_phys_addr(Self::alloc_page()); // allocate page for the first child PT
-        pt.entries[0].set_bit(BIT_PRESENT, true);
-        pt.entries[0].set_bit(BIT_WRITABLE, true);
-        pt.entries[0].set_bit(BIT_USER, true); // entry is present, writable and accessible by user
-        let pt0 = pt.entries[0].next_pt(); // get the child PT we just allocated
-        let cur_pt0 = get_page_table().entries[0].next_pt();
-        pt0.entries[3] = cur_pt0.entries[3].clone(); // copy over the entries 3, 4, 5, 6 from the equivalent
-        pt0.entries[4] = cur_pt0.entries[4].clone(); // child PT that is currently in use
-        pt0.entries[5] = cur_pt0.entries[5].clone(); // these correspond to the addresses our kernel uses
-        pt0.entries[6] = cur_pt0.entries[6].clone(); // plus some more, so that the entire physical memory is mapped
-        pt
+/// # Safety
+/// Only once at boot, while the bootloader's identity map is still in use.
+pub unsafe fn init_phys_map(boot_info: &BootInformation) {
+    // map the whole physical memory (as reported by the memory map, and at least the first 4 GiB
+    // which also hold the memory mapped devices) to the higher half using huge pages
+    let mem_end = boot_info
+        .memory_map_tag()
+        .expect("Must have memory map tag")
+        .memory_areas()
+        .iter()
+        .map(|area| area.end_address() as usize)
+        .fold(4 * GIB, usize::max);
+    let mem_end = mem_end.div_ceil(GIB) * GIB; // round up to whole GiBs
+    let gib_pages = core::arch::x86_64::__cpuid(0x80000001).edx & (1 << 26) != 0; // 1 GiB pages supported?
+    let options = BIT_PRESENT | BIT_WRITABLE | BIT_HUGE | BIT_NO_EXECUTE;
+    let pt = get_page_table();
+    let page_size = if gib_pages { GIB } else { HUGE_PAGE_SIZE };
+    // the first 4 GiB are already mapped by boot.asm (and the first GiB of that is shared with the
+    // kernel image mapping so we leave it alone)
+    for addr in (PHYS_MAP_END.load(Ordering::SeqCst)..mem_end).step_by(page_size) {
+        let virt = VirtAddr::new(PHYS_MAP_OFFSET + addr);
+        if gib_pages {
+            pt.map_gib_page(virt, PhysAddr::new(addr), options);
+        } else {
+            pt.map_virt_to_phys(virt, PhysAddr::new(addr), options);
+        }
+    }
+    PHYS_MAP_END.store(mem_end, Ordering::SeqCst);
+    crate::serial_println!(
+        "- PhysMap: mapped {:x} bytes at {:x} using {} pages",
+        mem_end,
+        PHYS_MAP_OFFSET,
+        if gib_pages { "1 GiB" } else { "2 MiB" }
+    );
+}
+
+/// # Safety
+/// Only once at boot, before any task's page table is created.
+pub unsafe fn init_kernel_space() {
+    // Make sure every P4 entry of the higher half points to a P3 table. Tasks copy these entries
+    // when they're created, so anything the kernel maps later on is visible to all of them.
+    let pt = get_page_table();
+    KERNEL_P4.store(pt.phys_addr().addr(), Ordering::SeqCst);
+    for i in KERNEL_SPACE_P4_START..512 {
+        let pte = pt.get_entry(i);
+        if !pte.get_bit(BIT_PRESENT) {
+            pte.set_phys_addr(PageTable::alloc_page().expect("out of memory for kernel page tables"));
+            pte.set_bit(BIT_PRESENT, true);
+            pte.set_bit(BIT_WRITABLE, true); // never BIT_USER, user space is the lower half only
+        }
+    }
+}
+
+/// # Safety
+/// Nothing may use user space addresses afterwards until a task's page table is loaded again.
+pub unsafe fn enable_kernel_space() {
+    // switch to the kernel's page table, ie. before the page table of the task we were running
+    // can be freed by another CPU
+    asm!("mov cr3, rax", in("rax") KERNEL_P4.load(Ordering::SeqCst));
+}
+
+pub fn kernel_p4() -> PhysAddr {
+    PhysAddr::new(KERNEL_P4.load(Ordering::SeqCst))
+}
+
+/// # Safety
+/// Only while starting the APs, nothing else may use the lower half meanwhile.
+pub unsafe fn set_low_identity_map(on: bool) {
+    // Map the start of physical memory at 0 in the kernel's page table (the same way the physical
+    // memory map does), for starting the other CPUs which turn on paging while running there.
+    // Nothing else runs on the kernel's page table at the time, user space has its own.
+    let pt: &mut PageTable = kernel_p4().to_virt().unwrap().to_ref();
+    let entry = if on { pt.entries[KERNEL_SPACE_P4_START] } else { PTEntry(0) };
+    pt.entries[0] = entry;
+    x86_64::instructions::tlb::flush_all();
+}
+
+// A task's page table. The P4 table, the lower half's page tables and the user pages mapped in
+// it all come from the frame allocator, and they're given back when it's dropped.
+// The threads of a process might have it loaded on several CPUs at once, so the frames of pages
+// unmapped from it are kept until whoever unmapped them had those CPUs flush their TLBs.
+pub struct AddressSpace {
+    p4: PhysAddr,
+    stack_top: usize,   // end of the user stack (0 if there's none)
+    stack_limit: usize, // lowest address the stack can grow to, the page below it is the guard page
+    file_mappings: Vec<FileMapping>, // mapped files, their pages are read in on faults
+    mmap_base: usize,                // where to look for room for mappings that don't ask for an address
+    unmapped: Vec<PhysAddr>,         // frames of unmapped pages that other CPUs might still reach, see take_unmapped
+}
+
+impl AddressSpace {
+    /// # Safety
+    /// The kernel's page table must be set up (see init_kernel_space).
+    pub unsafe fn try_new() -> Result<AddressSpace, OutOfMemory> {
+        // allocate the master PT struct, the lower half (user space) starts out empty
+        let p4 = PageTable::alloc_page()?;
+        let pt: &mut PageTable = p4.to_virt().unwrap().to_ref();
+        let cur_pt = get_page_table();
+        for i in KERNEL_SPACE_P4_START..512 {
+            // share the higher half with the kernel: physical memory map, kernel image etc.
+            pt.entries[i] = cur_pt.entries[i];
+        }
+        Ok(AddressSpace {
+            p4,
+            stack_top: 0,
+            stack_limit: 0,
+            file_mappings: Vec::new(),
+            mmap_base: MMAP_BASE + random_offset(MMAP_RANDOM_PAGES),
+            unmapped: Vec::new(),
+        })
+    }
+
+    /// # Safety
+    /// The table must not outlive the address space or be used through two references at once.
+    pub unsafe fn page_table(&self) -> &'static mut PageTable {
+        self.p4.to_virt().unwrap().to_ref()
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.p4
+    }
+
+    /// # Safety
+    /// The address space must stay alive as long as it's loaded in CR3 on any CPU.
+    pub unsafe fn enable(&self) {
+        asm!("mov cr3, rax", in("rax") self.p4.addr());
+    }
+
+    /// # Safety
+    /// virt must be a user space address.
+    pub unsafe fn map_new_page(&mut self, virt: VirtAddr, options: u64) -> Result<PhysAddr, OutOfMemory> {
+        // back a user page with a new zeroed frame (or return the frame already mapped there)
+        if let Some(pte) = self.page_table().get_mapping(virt) {
+            return Ok(pte.phys_addr());
+        }
+        let frame = frame_alloc::alloc_zeroed_frame().ok_or(OutOfMemory)?;
+        if let Err(e) = self.page_table().try_map_virt_to_phys(virt, frame, options) {
+            frame_alloc::free_frame(frame);
+            return Err(e);
+        }
+        Ok(frame)
+    }
+
+    pub fn mmap_base(&self) -> usize {
+        self.mmap_base
+    }
+
+    pub fn mmap_end(&self) -> usize {
+        // mappings must stay clear of the stack and its guard page
+        if self.stack_top == 0 {
+            USER_SPACE_END
+        } else {
+            self.stack_limit - FRAME_SIZE
+        }
+    }
+
+    pub fn file_mappings(&mut self) -> &mut Vec<FileMapping> {
+        &mut self.file_mappings
+    }
+
+    /// # Safety
+    /// frame must be a frame of the frame allocator that stays in use by its other owner.
+    pub unsafe fn map_frame(&mut self, virt: VirtAddr, frame: PhysAddr, options: u64) -> Result<(), OutOfMemory> {
+        // map a frame that's already in use somewhere else, taking a reference to it
+        frame_alloc::ref_frame(frame);
+        if let Err(e) = self.page_table().try_map_virt_to_phys(virt, frame, options) {
+            frame_alloc::free_frame(frame);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    /// The frame must only be freed once no CPU has the page in its TLB anymore.
+    pub unsafe fn unmap_page(&mut self, virt: VirtAddr) -> Result<Option<PTEntry>, OutOfMemory> {
+        // Remove a user page, returns the old entry. The reference to its frame is only dropped by
+        // whoever takes it from take_unmapped, it fails if there's no room to keep it until then
+        // (see reserve_unmapped).
+        let pte = match self.page_table().get_mapping(virt) {
+            Some(pte) => pte,
+            None => return Ok(None),
+        };
+        self.reserve_unmapped(1)?;
+        let old = *pte;
+        *pte = PTEntry(0);
+        asm!("invlpg [{}]", in(reg) virt.addr()); // in case it's the active address space
+        self.unmapped.push(old.phys_addr());
+        Ok(Some(old))
+    }
+
+    pub fn reserve_unmapped(&mut self, pages: usize) -> Result<(), OutOfMemory> {
+        // make sure that unmapping this many pages can't fail
+        self.unmapped.try_reserve(pages).map_err(|_| OutOfMemory)
+    }
+
+    pub fn take_unmapped(&mut self) -> Vec<PhysAddr> {
+        // the frames of the pages unmapped so far, to be freed once the other CPUs that might run
+        // on this page table flushed their TLBs
+        core::mem::take(&mut self.unmapped)
+    }
+
+    /// # Safety
+    /// Only once per address space, before the task starts.
+    pub unsafe fn map_stack(&mut self, size: usize, limit: usize) -> Result<VirtAddr, OutOfMemory> {
+        // Map `size` bytes of stack below USER_STACK_TOP (minus a random offset) and let it grow on
+        // faults up to `limit` bytes. The page below the limit is never mapped so that overflows
+        // fault there.
+        let size = size.div_ceil(FRAME_SIZE) * FRAME_SIZE;
+        let limit = core::cmp::max(size, limit.div_ceil(FRAME_SIZE) * FRAME_SIZE);
+        let top = USER_STACK_TOP - random_offset(STACK_RANDOM_PAGES);
+        for page in (top - size..top).step_by(FRAME_SIZE) {
+            self.map_new_page(VirtAddr::new(page), STACK_OPTIONS)?;
+        }
+        self.stack_top = top;
+        self.stack_limit = top - limit;
+        Ok(VirtAddr::new(top))
+    }
+
+    /// # Safety
+    /// Only from the page fault handler, for a fault in this address space.
+    pub unsafe fn grow_stack(&mut self, addr: usize) -> Option<PageFault> {
+        // handle a page fault at addr if it's in the stack or its guard page, None otherwise
+        if self.stack_top == 0 || addr >= self.stack_top || addr < self.stack_limit - FRAME_SIZE {
+            return None;
+        }
+        if addr < self.stack_limit {
+            return Some(PageFault::StackOverflow);
+        }
+        let page = VirtAddr::new(addr / FRAME_SIZE * FRAME_SIZE);
+        if self.page_table().get_mapping(page).is_some() {
+            return None; // already there, the fault is about something else (ie. protection)
+        }
+        match self.map_new_page(page, STACK_OPTIONS) {
+            Ok(_) => Some(PageFault::Handled),
+            Err(OutOfMemory) => Some(PageFault::OutOfMemory),
+        }
+    }
+
+    /// # Safety
+    /// The page tables must not be changed meanwhile.
+    pub unsafe fn user_pages(&self) -> usize {
+        // number of pages mapped in user space
+        Self::count_pages(self.p4, 4)
+    }
+
+    unsafe fn count_pages(table: PhysAddr, level: usize) -> usize {
+        let pt: &mut PageTable = table.to_virt().unwrap().to_ref();
+        let entries = if level == 4 { KERNEL_SPACE_P4_START } else { 512 };
+        pt.entries[..entries]
+            .iter()
+            .filter(|pte| pte.get_bit(BIT_PRESENT))
+            .map(|pte| {
+                if level == 1 || pte.get_bit(BIT_HUGE) {
+                    1
+                } else {
+                    Self::count_pages(pte.phys_addr(), level - 1)
+                }
+            })
+            .sum()
+    }
+
+    unsafe fn free_tables(table: PhysAddr, level: usize) {
+        // give back every user page and page table under this table
+        let pt: &mut PageTable = table.to_virt().unwrap().to_ref();
+        let entries = if level == 4 { KERNEL_SPACE_P4_START } else { 512 };
+        for pte in pt.entries[..entries].iter().filter(|pte| pte.get_bit(BIT_PRESENT)) {
+            if level == 1 {
+                frame_alloc::free_frame(pte.phys_addr()); // drop our reference to the user page
+            } else if !pte.get_bit(BIT_HUGE) {
+                Self::free_tables(pte.phys_addr(), level - 1);
+                frame_alloc::free_frame(pte.phys_addr());
+            }
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe {
+            if get_page_table().phys_addr().addr() == self.p4.addr() {
+                // we're still running on it, switch to the kernel's page table first
+                asm!("mov cr3, rax", in("rax") KERNEL_P4.load(Ordering::SeqCst));
+            }
+            Self::free_tables(self.p4, 4);
+            frame_alloc::free_frame(self.p4);
+            self.unmapped.drain(..).for_each(frame_alloc::free_frame); // nobody runs on it anymore
+        }
     }
+}
 
+impl PageTable {
     pub unsafe fn phys_addr(&self) -> PhysAddr {
         let virt = VirtAddr::new(self as *const _ as usize);
         virt.to_phys().unwrap().0
@@ -124,28 +489,91 @@ impl PageTable {
         asm!("mov cr3, rax", in("rax") phys_addr);
     }
 
-    unsafe fn alloc_page() -> PhysAddr {
-        let frame: Box<EmptyFrame> = Box::new([0; FRAME_SIZE as usize]);
-        VirtAddr::new(Box::into_raw(frame) as usize)
-            .to_phys()
-            .unwrap()
-            .0
+    unsafe fn alloc_page() -> Result<PhysAddr, OutOfMemory> {
+        // Page tables come from the frame allocator once it's up. Before that (ie. for the kernel's
+        // own page tables, which are never freed) they come from the heap.
+        if frame_alloc::frame_allocator_ready() {
+            return frame_alloc::alloc_zeroed_frame().ok_or(OutOfMemory);
+        }
+        let frame = alloc_zeroed(Layout::from_size_align_unchecked(FRAME_SIZE, FRAME_SIZE));
+        if frame.is_null() {
+            return Err(OutOfMemory); // let the caller decide if that's fatal
+        }
+        Ok(VirtAddr::new(frame as usize).to_phys().unwrap().0)
     }
 
     pub fn get_entry(&mut self, i: usize) -> &mut PTEntry {
         &mut self.entries[i]
     }
+
+    /// # Safety
+    /// The entry must not be used after the page table it's in was freed.
+    pub unsafe fn get_mapping(&mut self, virt: VirtAddr) -> Option<&'static mut PTEntry> {
+        // find the P1 entry mapping this (normal sized) page, without creating any tables
+        let mut pte = self.get_entry((virt.addr() >> 39) & 0b1_1111_1111);
+        for shift in [30, 21, 12].iter() {
+            if !pte.get_bit(BIT_PRESENT) || pte.get_bit(BIT_HUGE) {
+                return None;
+            }
+            pte = pte.next_pt().get_entry((virt.addr() >> shift) & 0b1_1111_1111);
+        }
+        if pte.get_bit(BIT_PRESENT) {
+            Some(&mut *(pte as *mut PTEntry))
+        } else {
+            None
+        }
+    }
+
+    /// # Safety
+    /// virt must be unused and phys a 1 GiB aligned range that's not handed out as frames.
+    pub unsafe fn map_gib_page(&mut self, virt: VirtAddr, phys: PhysAddr, create_options: u64) -> &'static PTEntry {
+        // map a 1 GiB huge page directly from the P3 table
+        let p4_off = (virt.addr() >> 39) & 0b1_1111_1111;
+        let pte = self.get_entry(p4_off);
+        if !pte.get_bit(BIT_PRESENT) {
+            let new_frame = Self::alloc_page().expect("out of memory for kernel page tables");
+            pte.set_phys_addr(new_frame);
+            pte.set_bit(BIT_PRESENT, true);
+        }
+        if (create_options & BIT_WRITABLE) != 0 {
+            pte.set_bit(BIT_WRITABLE, true);
+        }
+        let p3_off = (virt.addr() >> 30) & 0b1_1111_1111;
+        let pte = pte.next_pt().get_entry(p3_off);
+        pte.set_phys_addr(phys);
+        pte.set_opts(create_options | BIT_HUGE);
+        pte
+    }
+
     pub unsafe fn map_virt_to_phys(
         &mut self,
         virt: VirtAddr,
         phys: PhysAddr,
-        create_options: u16,
+        create_options: u64,
     ) -> &'static PTEntry {
+        // for the kernel's own mappings, running out of memory here is fatal
+        self.try_map_virt_to_phys(virt, phys, create_options)
+            .expect("out of memory for page tables")
+    }
+
+    /// # Safety
+    /// Same as map_virt_to_phys: nothing may still use what virt mapped before.
+    pub unsafe fn try_map_virt_to_phys(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        create_options: u64,
+    ) -> Result<&'static PTEntry, OutOfMemory> {
+        assert!(
+            create_options & BIT_USER == 0 || virt.addr() < USER_SPACE_END,
+            "user mapping in kernel space: {}",
+            virt
+        );
         let create_huge = (create_options & BIT_HUGE) != 0;
         let p4_off = (virt.addr() >> 39) & 0b1_1111_1111;
         let pte = self.get_entry(p4_off as usize);
         if !pte.get_bit(BIT_PRESENT) {
-            let new_frame = Self::alloc_page();
+            let new_frame = Self::alloc_page()?;
             pte.set_phys_addr(new_frame);
             pte.set_bit(BIT_PRESENT, true);
         }
@@ -158,7 +586,7 @@ impl PageTable {
         let p3_off = (virt.addr() >> 30) & 0b1_1111_1111;
         let pte = pte.next_pt().get_entry(p3_off as usize);
         if !pte.get_bit(BIT_PRESENT) || pte.get_bit(BIT_HUGE) {
-            let new_frame = Self::alloc_page();
+            let new_frame = Self::alloc_page()?;
             pte.set_phys_addr(new_frame);
             pte.set_bit(BIT_PRESENT, true);
         }
@@ -174,9 +602,9 @@ impl PageTable {
             if create_huge {
                 pte.set_phys_addr(phys);
                 pte.set_opts(create_options);
-                return pte;
+                return Ok(pte);
             } else {
-                let new_frame = Self::alloc_page();
+                let new_frame = Self::alloc_page()?;
                 pte.set_phys_addr(new_frame);
                 pte.set_bit(BIT_PRESENT, true);
             }
@@ -191,7 +619,7 @@ impl PageTable {
         let pte = pte.next_pt().get_entry(p1_off as usize);
         pte.set_phys_addr(phys);
         pte.set_opts(create_options);
-        return pte;
+        Ok(pte)
     }
 }
 
@@ -256,8 +684,8 @@ impl PhysAddr {
     }
 
     pub unsafe fn to_virt(&self) -> Option<VirtAddr> {
-        if self.0 < 0x100000000 {
-            Some(VirtAddr::new(self.0 + VIRT_OFFSET))
+        if self.0 < PHYS_MAP_END.load(Ordering::Relaxed) {
+            Some(VirtAddr::new(self.0 + PHYS_MAP_OFFSET))
         } else {
             None
         }
@@ -0,0 +1,289 @@
+use crate::fat16::{DirEntry, FAT16};
+use crate::frame_alloc;
+use crate::mem::{self, AddressSpace, PageFault, PhysAddr, VirtAddr, FRAME_SIZE};
+use crate::serial_println;
+use alloc::collections::btree_map::Entry;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::arch::asm;
+use spin::Mutex;
+
+// Memory mapped files. mmap only records the mapping, the pages are read in from the disk when
+// the task first touches them. Pages that were read are kept in a page cache which holds a
+// reference to each frame: shared mappings map these frames directly (so every task sees the
+// same data), private ones map them read-only and copy them on the first write.
+
+pub const MAP_SHARED: u64 = 1; // writes are seen by every task mapping the file and written back to it
+pub const MAP_PRIVATE: u64 = 2; // writes go to a private copy of the page
+pub const PROT_WRITE: u64 = 4; // the mapping is writable (it's always readable)
+const MAX_MAP_LEN: usize = 1 << 26;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmapError {
+    NotFound,        // no such file
+    InvalidArgument, // bad flags, unaligned offset or length, or a directory
+    BadAddress,      // not page aligned, not in user space or overlapping another mapping
+    OutOfMemory,
+    ReadOnlyFs,      // dirty pages can't be written back to the file
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FileMapping {
+    start: usize,
+    end: usize,
+    inode: u16,
+    offset: usize, // offset in the file of the first page
+    shared: bool,
+    writable: bool,
+}
+
+static PAGE_CACHE: Mutex<BTreeMap<(u16, usize), PhysAddr>> = Mutex::new(BTreeMap::new()); // (inode, page in the file) -> frame
+
+impl FileMapping {
+    fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn file_page(&self, page: usize) -> usize {
+        (self.offset + page - self.start) / FRAME_SIZE
+    }
+
+    fn page_options(&self, writable: bool) -> u64 {
+        let options = mem::BIT_PRESENT | mem::BIT_USER | mem::BIT_NO_EXECUTE;
+        if writable {
+            options | mem::BIT_WRITABLE
+        } else {
+            options
+        }
+    }
+}
+
+pub fn overlaps(space: &mut AddressSpace, start: usize, end: usize) -> bool {
+    space.file_mappings().iter().any(|m| m.start < end && start < m.end)
+}
+
+/// # Safety
+/// space's page tables must not be changed meanwhile.
+pub unsafe fn find_free_range(space: &mut AddressSpace, len: usize) -> Option<usize> {
+    // room for len bytes of mappings, looking up from the (randomized) mmap base of the task
+    let mut addr = space.mmap_base();
+    while addr.checked_add(len)? <= space.mmap_end() {
+        if let Some(m) = space.file_mappings().iter().find(|m| m.start < addr + len && addr < m.end) {
+            addr = m.end;
+            continue;
+        }
+        let pt = space.page_table();
+        match (addr..addr + len).step_by(FRAME_SIZE).find(|page| pt.get_mapping(VirtAddr::new(*page)).is_some()) {
+            Some(page) => addr = page + FRAME_SIZE,
+            None => return Some(addr),
+        }
+    }
+    None
+}
+
+/// # Safety
+/// space must be the current task's address space.
+pub unsafe fn mmap(
+    space: &mut AddressSpace,
+    file: &DirEntry,
+    offset: usize,
+    len: usize,
+    addr: usize,
+    flags: u64,
+) -> Result<usize, MmapError> {
+    // map len bytes of the file starting at offset to addr (or wherever there's room if it's 0),
+    // nothing is read until it's accessed
+    let shared = flags & MAP_SHARED != 0;
+    if shared == (flags & MAP_PRIVATE != 0) || len == 0 || len > MAX_MAP_LEN || !offset.is_multiple_of(FRAME_SIZE) {
+        return Err(MmapError::InvalidArgument);
+    }
+    let len = len.div_ceil(FRAME_SIZE) * FRAME_SIZE;
+    let addr = if addr == 0 {
+        find_free_range(space, len).ok_or(MmapError::OutOfMemory)?
+    } else {
+        addr
+    };
+    if addr % FRAME_SIZE != 0 || crate::usercopy::validate_user_range(addr, len).is_err() {
+        return Err(MmapError::BadAddress);
+    }
+    let pt = space.page_table();
+    if overlaps(space, addr, addr + len)
+        || (addr..addr + len).step_by(FRAME_SIZE).any(|page| pt.get_mapping(VirtAddr::new(page)).is_some())
+    {
+        return Err(MmapError::BadAddress);
+    }
+    if file.is_dir() || offset > file.size {
+        return Err(MmapError::InvalidArgument);
+    }
+    let mappings = space.file_mappings();
+    mappings.try_reserve(1).map_err(|_| MmapError::OutOfMemory)?;
+    mappings.push(FileMapping {
+        start: addr,
+        end: addr + len,
+        inode: file.index,
+        offset,
+        shared,
+        writable: flags & PROT_WRITE != 0,
+    });
+    Ok(addr)
+}
+
+fn cached_page(inode: u16, file_page: usize) -> Option<PhysAddr> {
+    x86_64::instructions::interrupts::without_interrupts(|| PAGE_CACHE.lock().get(&(inode, file_page)).copied())
+}
+
+pub fn read_page(inode: u16, file_page: usize) -> Result<(), mem::OutOfMemory> {
+    // Read a page of the file into the page cache. This waits for the disk so it's called without
+    // any locks held, if someone else read the same page meanwhile their frame is kept.
+    let frame = frame_alloc::alloc_zeroed_frame().ok_or(mem::OutOfMemory)?;
+    let fat = FAT16::new();
+    if let Some(file) = fat.at(inode) {
+        let buf = unsafe { core::slice::from_raw_parts_mut(frame.to_virt().unwrap().addr() as *mut u8, FRAME_SIZE) };
+        let read = fat.read_at(&file, file_page * FRAME_SIZE, buf); // past the end of the file stays zeroed
+        serial_println!("mmap: read {} bytes of page {} of inode {} to {}", read, file_page, inode, frame);
+    }
+    let raced = x86_64::instructions::interrupts::without_interrupts(|| match PAGE_CACHE.lock().entry((inode, file_page)) {
+        Entry::Occupied(_) => true,
+        Entry::Vacant(entry) => {
+            entry.insert(frame);
+            false
+        }
+    });
+    if raced {
+        frame_alloc::free_frame(frame);
+    }
+    Ok(())
+}
+
+/// # Safety
+/// Only from the page fault handler, for a fault in this address space.
+pub unsafe fn handle_fault(space: &mut AddressSpace, addr: usize, write: bool) -> Option<PageFault> {
+    // bring in a page of a mapped file (or copy it on write for a private mapping), None if the
+    // fault isn't in a mapped file or is a real access violation
+    let mapping = *space.file_mappings().iter().find(|m| m.contains(addr))?;
+    if write && !mapping.writable {
+        return None;
+    }
+    let page = VirtAddr::new(addr / FRAME_SIZE * FRAME_SIZE);
+    let present = space.page_table().get_mapping(page).map(|pte| *pte);
+    let res = match present {
+        None => {
+            let file_page = mapping.file_page(page.addr());
+            let frame = match cached_page(mapping.inode, file_page) {
+                Some(frame) => frame,
+                None => return Some(PageFault::NeedsPage(mapping.inode, file_page)),
+            };
+            if write && !mapping.shared {
+                copy_page(space, page, frame, mapping.page_options(true))
+            } else {
+                // private mappings are read-only until the first write
+                space.map_frame(page, frame, mapping.page_options(mapping.shared && mapping.writable))
+            }
+        }
+        Some(pte) if write && !mapping.shared && !pte.get_bit(mem::BIT_WRITABLE) => {
+            // copy on write, the page cache still holds the original (and the page isn't freed
+            // until the other threads of the process can't read it through their TLBs anymore)
+            space.unmap_page(page).and_then(|_| copy_page(space, page, pte.phys_addr(), mapping.page_options(true)))
+        }
+        Some(pte) if !write || pte.get_bit(mem::BIT_WRITABLE) => {
+            // another thread of the process got to it first (or this CPU had the old entry cached)
+            asm!("invlpg [{}]", in(reg) page.addr());
+            return Some(PageFault::Handled);
+        }
+        Some(_) => return None,
+    };
+    match res {
+        Ok(()) => Some(PageFault::Handled),
+        Err(mem::OutOfMemory) => Some(PageFault::OutOfMemory),
+    }
+}
+
+unsafe fn copy_page(space: &mut AddressSpace, page: VirtAddr, from: PhysAddr, options: u64) -> Result<(), mem::OutOfMemory> {
+    let frame = space.map_new_page(page, options)?;
+    core::ptr::copy_nonoverlapping(
+        from.to_virt().unwrap().addr() as *const u8,
+        frame.to_virt().unwrap().addr() as *mut u8,
+        FRAME_SIZE,
+    );
+    Ok(())
+}
+
+fn write_back(inode: u16, file_page: usize, frame: PhysAddr) -> Result<(), MmapError> {
+    // FAT16 is read-only for now, the changes only live in the page cache
+    serial_println!("mmap: can't write back page {} of inode {} ({})", file_page, inode, frame);
+    Err(MmapError::ReadOnlyFs)
+}
+
+/// # Safety
+/// space must be the current task's address space.
+pub unsafe fn msync(space: &mut AddressSpace, addr: usize, len: usize) -> Result<(), MmapError> {
+    // write the dirty pages of shared mappings in the range back to their files
+    let end = addr.checked_add(len).ok_or(MmapError::BadAddress)?;
+    let mut res = Ok(());
+    let mappings: Vec<FileMapping> = space
+        .file_mappings()
+        .iter()
+        .filter(|m| m.shared && m.writable && m.start < end && addr < m.end)
+        .copied()
+        .collect();
+    for mapping in mappings.iter() {
+        let start = core::cmp::max(mapping.start, addr / FRAME_SIZE * FRAME_SIZE);
+        for page in (start..core::cmp::min(mapping.end, end)).step_by(FRAME_SIZE) {
+            let pte = match space.page_table().get_mapping(VirtAddr::new(page)) {
+                Some(pte) if pte.get_bit(mem::BIT_DIRTY) => pte,
+                _ => continue,
+            };
+            match write_back(mapping.inode, mapping.file_page(page), pte.phys_addr()) {
+                Ok(()) => pte.set_bit(mem::BIT_DIRTY, false),
+                Err(e) => res = Err(e), // keep going, and keep it dirty for the next time
+            }
+        }
+    }
+    res
+}
+
+/// # Safety
+/// space must be the current task's address space, its TLB entries are only flushed on this CPU.
+pub unsafe fn munmap(space: &mut AddressSpace, addr: usize, len: usize) -> Result<(), MmapError> {
+    // unmap the mapped files in the range, splitting the mappings that are only partly in it
+    if !addr.is_multiple_of(FRAME_SIZE) {
+        return Err(MmapError::BadAddress);
+    }
+    let end = addr.checked_add(len).ok_or(MmapError::BadAddress)?;
+    let end = end.div_ceil(FRAME_SIZE) * FRAME_SIZE;
+    match msync(space, addr, end - addr) {
+        Ok(()) | Err(MmapError::ReadOnlyFs) => {} // the changes stay in the page cache
+        Err(e) => return Err(e),
+    }
+    let mut kept = Vec::new();
+    kept.try_reserve(space.file_mappings().len() + 1).map_err(|_| MmapError::OutOfMemory)?;
+    let pages: usize = space
+        .file_mappings()
+        .iter()
+        .filter(|m| m.start < end && addr < m.end)
+        .map(|m| (m.end.min(end) - m.start.max(addr)) / FRAME_SIZE)
+        .sum();
+    space.reserve_unmapped(pages).map_err(|_| MmapError::OutOfMemory)?;
+    let mappings = core::mem::take(space.file_mappings());
+    for mapping in mappings.into_iter() {
+        if mapping.end <= addr || end <= mapping.start {
+            kept.push(mapping);
+            continue;
+        }
+        for page in (core::cmp::max(mapping.start, addr)..core::cmp::min(mapping.end, end)).step_by(FRAME_SIZE) {
+            let _ = space.unmap_page(VirtAddr::new(page)); // there's room for it, reserved above
+        }
+        if mapping.start < addr {
+            kept.push(FileMapping { end: addr, ..mapping });
+        }
+        if end < mapping.end {
+            kept.push(FileMapping {
+                start: end,
+                offset: mapping.offset + (end - mapping.start),
+                ..mapping
+            });
+        }
+    }
+    *space.file_mappings() = kept;
+    Ok(())
+}
@@ -86,7 +86,9 @@ const PIC_SLAVE_NEW_OFFSET: u8 = 0x28;
 
 const END_OF_INTERRUPT: u8 = 0x20;
 
-pub fn init_pics() {
+pub fn init_pics(mask_all: bool) {
+    // remap the PICs out of the way of the CPU exceptions, masking all of their IRQs when the
+    // APIC handles them instead
     let master_cmd: Port<u8> = Port::new(PIC_MASTER_PORT);
     let master_data: Port<u8> = Port::new(PIC_MASTER_PORT + 1);
     let slave_cmd: Port<u8> = Port::new(PIC_SLAVE_PORT);
@@ -129,8 +131,13 @@ pub fn init_pics() {
     wait();
 
     // restore interrupt masks
-    master_data.write(a1);
-    slave_data.write(a2);
+    if mask_all {
+        master_data.write(0xFF);
+        slave_data.write(0xFF);
+    } else {
+        master_data.write(a1);
+        slave_data.write(a2);
+    }
 
     println!(" - Enabling interrupts");
     unsafe {
@@ -142,18 +149,52 @@ pub fn init_pics() {
 }
 
 pub fn end_of_interrupt(interrupt_id: u8) {
+    if crate::apic::enabled() {
+        crate::apic::end_of_interrupt(); // the PICs are masked
+        return;
+    }
     if interrupt_id >= PIC_SLAVE_NEW_OFFSET && interrupt_id < PIC_SLAVE_NEW_OFFSET + 8 {
         Port::new(PIC_SLAVE_PORT).write(END_OF_INTERRUPT);
     }
     Port::new(PIC_MASTER_PORT).write(END_OF_INTERRUPT);
 }
 
-pub fn disable_pit() {
-    const PIT_COMMAND_PORT: u16 = 0x43;
-    const PIT_CHANNEL0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+const PIT_CHANNEL0_PORT: u16 = 0x40;
+const PIT_CHANNEL2_PORT: u16 = 0x42;
+const PIT_GATE_PORT: u16 = 0x61; // bit 0 gates channel 2, bit 5 is its output
+pub const PIT_HZ: u64 = 1193182; // the PIT's input clock
 
+pub fn disable_pit() {
     Port::<u8>::new(PIT_COMMAND_PORT).write(0x30); // select channel 0
     let chan: Port<u8> = Port::new(PIT_CHANNEL0_PORT);
     chan.write(0);
     chan.write(0); // set freq to 0
-}
\ No newline at end of file
+}
+
+pub fn set_pit_frequency(hz: u64) -> u16 {
+    // Make channel 0 (the timer IRQ) fire hz times a second, as close as the divisor allows.
+    // Returns the divisor, where 0 means 65536 (~18.2 Hz, the slowest it can go).
+    let divisor = (PIT_HZ / hz.max(1)).max(1);
+    let divisor = if divisor > 0xFFFF { 0 } else { divisor as u16 };
+    Port::<u8>::new(PIT_COMMAND_PORT).write(0x34); // channel 0, lobyte/hibyte, rate generator
+    let chan: Port<u8> = Port::new(PIT_CHANNEL0_PORT);
+    chan.write(divisor as u8);
+    chan.write((divisor >> 8) as u8);
+    divisor
+}
+
+pub fn pit_wait(count: u16) {
+    // busy wait for count PIT clocks using channel 2, which isn't connected to anything but the
+    // speaker (kept off)
+    let gate: Port<u8> = Port::new(PIT_GATE_PORT);
+    gate.write((gate.read() & !2) | 1); // speaker off, gate on
+    Port::<u8>::new(PIT_COMMAND_PORT).write(0xB0); // channel 2, lobyte/hibyte, interrupt on terminal count
+    let chan: Port<u8> = Port::new(PIT_CHANNEL2_PORT);
+    chan.write(count as u8);
+    chan.write((count >> 8) as u8);
+    let val = gate.read();
+    gate.write(val & !1); // restart the count by toggling the gate
+    gate.write(val | 1);
+    while gate.read() & 0x20 == 0 {} // output goes high when the count is done
+}
@@ -0,0 +1,59 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// Random numbers for address space layout randomization. They come from RDRAND when the CPU has
+// it, else from a xorshift generator that mixes in the low bits of the TSC on every call. That's
+// nowhere near cryptographic but the timing of task creation makes it hard enough to guess.
+
+static HAS_RDRAND: AtomicBool = AtomicBool::new(false);
+static STATE: AtomicU64 = AtomicU64::new(0x2545F4914F6CDD1D); // xorshift state, never 0
+
+/// # Safety
+/// Only once at boot, before anything asks for random numbers.
+pub unsafe fn init_random() -> bool {
+    // returns whether RDRAND is used
+    let has_rdrand = core::arch::x86_64::__cpuid(1).ecx & (1 << 30) != 0;
+    HAS_RDRAND.store(has_rdrand, Ordering::SeqCst);
+    STATE.fetch_xor(rdtsc(), Ordering::Relaxed);
+    has_rdrand
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+fn rdrand() -> Option<u64> {
+    // RDRAND can fail if the hardware generator is drained, it's fine to retry a few times
+    for _ in 0..10 {
+        let val: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}; setc {}", out(reg) val, out(reg_byte) ok);
+        }
+        if ok != 0 {
+            return Some(val);
+        }
+    }
+    None
+}
+
+fn xorshift() -> u64 {
+    let mut x = STATE.load(Ordering::Relaxed) ^ (rdtsc() & 0xff); // the low TSC bits jitter between calls
+    if x == 0 {
+        x = 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    STATE.store(x, Ordering::Relaxed);
+    x
+}
+
+pub fn random() -> u64 {
+    if HAS_RDRAND.load(Ordering::Relaxed) {
+        if let Some(val) = rdrand() {
+            return val;
+        }
+    }
+    xorshift()
+}
@@ -0,0 +1,162 @@
+use crate::mem::OutOfMemory;
+use crate::time;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+// How the scheduler picks the next task out of a CPU's run queue. Every CPU has its own policy
+// object holding the pids of the runnable tasks waiting for it, while the tasks' nice values and
+// virtual runtimes live in their SchedParams. The run queues are only touched with interrupts
+// off, so nothing in here allocates except for reserve (which can fail).
+
+pub const NICE_MIN: i8 = -20; // most favoured
+pub const NICE_MAX: i8 = 19;
+const NICE_0_WEIGHT: u64 = 1024;
+
+// Share of the CPU of each nice value for the fair policy, each step is about 25% (same as Linux)
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, // -20 to -11
+    9548, 7620, 6100, 4904, 3906, 3121, 2501, 1991, 1586, 1277, // -10 to -1
+    1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, // 0 to 9
+    110, 87, 70, 56, 45, 36, 29, 23, 18, 15, // 10 to 19
+];
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SchedParams {
+    pub nice: i8,
+    pub vruntime: u64, // ns it ran, scaled by its weight (only used by the fair policy)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyKind {
+    Priority, // the most favoured runnable tasks take turns, the others wait for them
+    Fair,     // everyone runs, for a share of the time depending on their nice value
+}
+
+impl PolicyKind {
+    pub fn from_name(name: &str) -> Option<PolicyKind> {
+        match name {
+            "prio" => Some(PolicyKind::Priority),
+            "fair" => Some(PolicyKind::Fair),
+            _ => None,
+        }
+    }
+
+    pub fn create(self) -> Box<dyn SchedPolicy> {
+        match self {
+            PolicyKind::Priority => Box::new(PriorityRoundRobin::new()),
+            PolicyKind::Fair => Box::new(FairShare::new()),
+        }
+    }
+}
+
+pub trait SchedPolicy: Send {
+    fn name(&self) -> &'static str;
+    fn reserve(&mut self, tasks: usize) -> Result<(), OutOfMemory>; // make room for this many tasks so that enqueue never allocates
+    fn enqueue(&mut self, pid: usize, params: &mut SchedParams);
+    fn pick_next(&mut self) -> Option<usize>; // removes it from the queue
+    fn remove(&mut self, pid: usize);
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn ran(&mut self, params: &mut SchedParams, ns: u64); // a task ran for ns on this CPU
+}
+
+#[derive(Default)]
+pub struct PriorityRoundRobin {
+    queue: Vec<(i8, u64, usize)>, // (nice, arrival, pid), the lowest runs next
+    arrivals: u64,
+}
+
+impl PriorityRoundRobin {
+    pub fn new() -> PriorityRoundRobin {
+        PriorityRoundRobin {
+            queue: Vec::new(),
+            arrivals: 0,
+        }
+    }
+}
+
+impl SchedPolicy for PriorityRoundRobin {
+    fn name(&self) -> &'static str {
+        "priority round-robin"
+    }
+
+    fn reserve(&mut self, tasks: usize) -> Result<(), OutOfMemory> {
+        self.queue.try_reserve(tasks.saturating_sub(self.queue.len())).map_err(|_| OutOfMemory)
+    }
+
+    fn enqueue(&mut self, pid: usize, params: &mut SchedParams) {
+        self.arrivals += 1; // tasks of the same priority run in the order they got here
+        self.queue.push((params.nice, self.arrivals, pid));
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        let (idx, _) = self.queue.iter().enumerate().min_by_key(|(_, entry)| (entry.0, entry.1))?;
+        Some(self.queue.swap_remove(idx).2)
+    }
+
+    fn remove(&mut self, pid: usize) {
+        self.queue.retain(|entry| entry.2 != pid);
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn ran(&mut self, _params: &mut SchedParams, _ns: u64) {}
+}
+
+#[derive(Default)]
+pub struct FairShare {
+    queue: Vec<(u64, usize)>, // (vruntime, pid), the one that ran the least runs next
+    min_vruntime: u64,        // of the last task picked, never goes back
+}
+
+impl FairShare {
+    pub fn new() -> FairShare {
+        FairShare {
+            queue: Vec::new(),
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl SchedPolicy for FairShare {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn reserve(&mut self, tasks: usize) -> Result<(), OutOfMemory> {
+        self.queue.try_reserve(tasks.saturating_sub(self.queue.len())).map_err(|_| OutOfMemory)
+    }
+
+    fn enqueue(&mut self, pid: usize, params: &mut SchedParams) {
+        // A task that slept (or is new) would run until it caught up with the others, it gets
+        // only one tick ahead of them instead. That's still enough for the shell to go first
+        // when a key is pressed.
+        let floor = self.min_vruntime.saturating_sub(time::tick_ns());
+        params.vruntime = params.vruntime.max(floor);
+        self.queue.push((params.vruntime, pid));
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        let (idx, _) = self.queue.iter().enumerate().min_by_key(|(_, entry)| entry.0)?;
+        let (vruntime, pid) = self.queue.swap_remove(idx);
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(pid)
+    }
+
+    fn remove(&mut self, pid: usize) {
+        self.queue.retain(|entry| entry.1 != pid);
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn ran(&mut self, params: &mut SchedParams, ns: u64) {
+        let weight = WEIGHTS[(params.nice - NICE_MIN) as usize];
+        params.vruntime = params.vruntime.saturating_add(ns * NICE_0_WEIGHT / weight);
+    }
+}
@@ -1,16 +1,27 @@
 use core::arch::asm;
+use crate::fpu::FpuState;
+use crate::frame_alloc;
 use crate::gdt;
 use crate::mem;
+use crate::mmap;
 use crate::port;
-use crate::serial_println;
+use crate::sched_policy::{self, PolicyKind, SchedParams, SchedPolicy};
+use crate::smp;
+use crate::time;
+use crate::{apic, println, serial_println};
 use alloc::boxed::Box;
+use alloc::collections::BinaryHeap;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::cmp::Reverse;
 use core::fmt::Display;
-use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Context {
     pub rbp: u64,
     pub rax: u64,
@@ -48,7 +59,8 @@ pub unsafe fn get_context() -> *const Context {
 pub unsafe fn restore_context(ctxr: &Context) {
     asm!("mov rsp, {};\
     pop rbp; pop rax; pop rbx; pop rcx; pop rdx; pop rsi; pop rdi; pop r8; pop r9;\
-    pop r10; pop r11; pop r12; pop r13; pop r14; pop r15; iretq;",
+    pop r10; pop r11; pop r12; pop r13; pop r14; pop r15;\
+    test byte ptr [rsp + 8], 3; jz 2f; swapgs; 2: iretq;", // user space's gs if going back there
     in(reg) ctxr);
 }
 
@@ -62,6 +74,7 @@ pub unsafe fn jmp_to_usermode(code: mem::VirtAddr, stack_end: mem::VirtAddr) {
     push 0x200 // rflags (only interrupt bit set)
     push rdx   // code segment
     push rdi   // ret to virtual addr
+    swapgs     // user space's gs
     iretq",
     in("rdi") code.addr(), in("rsi") stack_end.addr(), in("dx") cs_idx, in("ax") ds_idx);
 }
@@ -73,149 +86,888 @@ enum TaskState {
     StartingInfo(mem::VirtAddr, mem::VirtAddr), // or a starting instruction and stack pointer
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskStatus {
+    Runnable,      // can be picked by run_next
+    Blocked,       // waiting in a WaitQueue
+    Sleeping(u64), // waiting until the given timer tick
+    Zombie(i64),   // exited with the given code, kept until its parent waits for it
+}
+
+pub const YIELD_VECTOR: u8 = 0x81;
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+const NOT_IDLE: u64 = u64::MAX;
+static IDLE_NS: [AtomicU64; smp::MAX_CPUS] = [const { AtomicU64::new(0) }; smp::MAX_CPUS]; // time each CPU's idle task spent halted
+static IDLE_SINCE: [AtomicU64; smp::MAX_CPUS] = [const { AtomicU64::new(NOT_IDLE) }; smp::MAX_CPUS]; // uptime when it halted, NOT_IDLE while it's not
+static TIMER_QUEUE: Mutex<BinaryHeap<Reverse<(u64, usize)>>> = Mutex::new(BinaryHeap::new()); // (wake up tick, pid) of sleeping tasks, earliest first
+
+const KERNEL_STACK_SIZE: usize = 0x10000;
+const MSR_FS_BASE: u32 = 0xC0000100;
+
+struct KernelStack(Vec<u8>); // used for the task's syscalls and interrupts from user mode
+
+impl KernelStack {
+    fn try_new() -> Result<KernelStack, mem::OutOfMemory> {
+        let mut stack = Vec::new();
+        stack.try_reserve_exact(KERNEL_STACK_SIZE).map_err(|_| mem::OutOfMemory)?;
+        stack.resize(KERNEL_STACK_SIZE, 0);
+        Ok(KernelStack(stack))
+    }
+
+    fn end(&self) -> u64 {
+        (self.0.as_ptr() as u64 + KERNEL_STACK_SIZE as u64) & !0xf
+    }
+}
+
+type SharedSpace = Arc<Mutex<mem::AddressSpace>>; // the threads of a process share it
+
+pub struct Unmapped {
+    // pages that were unmapped from a task's address space, which other CPUs running its threads
+    // might still have in their TLBs
+    cpus: u32,
+    frames: Vec<mem::PhysAddr>,
+}
+
+impl Unmapped {
+    fn release(self) {
+        // make the other CPUs flush their TLBs and only then free the frames
+        if !self.frames.is_empty() {
+            smp::tlb_shootdown(self.cpus);
+        }
+        self.frames.into_iter().for_each(frame_alloc::free_frame);
+    }
+}
+
+struct Remains {
+    // what's left to free of a task that exited, outside of the scheduler's locks
+    space: Option<SharedSpace>, // only freed along with the last thread
+    kernel_stack: KernelStack,
+}
+
 pub struct Task {
-    state: TaskState,             // the current state of the task
-    task_pt: Box<mem::PageTable>, // the page table for this task
-    _data_bytes: Pin<Box<[u8]>>,          // a vector to keep the task's data to be mapped
-    _stack_bytes: Pin<Box<[u8]>>,          // a vector to keep the task's stack space
+    pid: usize,                         // also the thread id
+    group: usize,                       // pid of the first thread of its process
+    parent: usize,                      // pid of the task that spawned it, 0 for the kernel
+    status: TaskStatus,
+    on_cpu: Option<usize>,              // the CPU running it, its kernel stack is in use until it's released there
+    last_cpu: usize,                    // whose run queue it goes back to
+    sched: SchedParams,                 // nice value etc. for the scheduling policy
+    run_start: u64,                     // uptime in ns when it last got a CPU
+    state: TaskState,                   // the current state of the task
+    task_pt: Option<SharedSpace>,       // the page table for this task, owns the task's memory (None once it exited)
+    kernel_stack: KernelStack,
+    fpu: FpuState,                      // x87 / SSE / AVX registers while it's not running
+    fs_base: u64,                       // for thread local storage in user space
 }
 
 impl Task {
+    fn with_state(state: TaskState, task_pt: Option<SharedSpace>) -> Result<Task, mem::OutOfMemory> {
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        Ok(Task {
+            pid,
+            group: pid,
+            parent: 0,
+            status: TaskStatus::Runnable,
+            on_cpu: None,
+            last_cpu: 0,
+            sched: SchedParams::default(),
+            run_start: 0,
+            state,
+            task_pt,
+            kernel_stack: KernelStack::try_new()?,
+            fpu: FpuState::try_new()?,
+            fs_base: 0,
+        })
+    }
+
     pub fn new(
         exec_base: mem::VirtAddr,
         stack_end: mem::VirtAddr,
-        task_pt: Box<mem::PageTable>,
-        _data_bytes: Pin<Box<[u8]>>,
-        _stack_bytes: Pin<Box<[u8]>>,
-    ) -> Task {
-        // ask for the vecs to be pinned as we take the pointer to the data above
-        // and we don't want the data to be moved around in physical memory while we've mapped it to virtual memory
-        Task {
-            state: TaskState::StartingInfo(exec_base, stack_end),
-            task_pt,
-            _data_bytes,
-            _stack_bytes,
+        task_pt: mem::AddressSpace,
+    ) -> Result<Task, mem::OutOfMemory> {
+        Self::with_state(
+            TaskState::StartingInfo(exec_base, stack_end),
+            Some(Arc::new(Mutex::new(task_pt))),
+        )
+    }
+
+    pub fn kernel_thread(f: impl FnOnce() + Send + 'static) -> Result<Task, mem::OutOfMemory> {
+        // A task that runs f in ring 0 on its kernel stack and the kernel's page table, and exits
+        // once f returns. It starts out as if it had been preempted right before kthread_entry.
+        let mut task = Self::with_state(TaskState::SavedContext(Context::default()), None)?; // never has any user space
+        let f: Box<dyn FnOnce() + Send> = Box::new(f);
+        let (cs, ss) = gdt::kernel_segs();
+        task.state = TaskState::SavedContext(Context {
+            rdi: Box::into_raw(Box::new(f)) as u64, // a thin pointer to the closure
+            rip: kthread_entry as *const () as u64,
+            cs: cs as u64,
+            rflags: 0x200, // interrupts on, it's preempted like any other task
+            rsp: task.kernel_stack.end() - 8, // the return address kthread_entry never uses
+            ss: ss as u64,
+            ..Context::default()
+        });
+        Ok(task)
+    }
+
+    fn user_thread(entry: u64, stack_top: u64, arg: u64) -> Result<Task, mem::OutOfMemory> {
+        // a thread starting at entry(arg) in user space, on a stack the process allocated itself
+        // (the process's address space is filled in by spawn_thread)
+        let (cs, ss) = gdt::user_segs();
+        let ctx = Context {
+            rdi: arg,
+            rip: entry,
+            cs: cs as u64,
+            rflags: 0x200,
+            rsp: (stack_top & !0xf) - 8, // aligned as if entry had been called
+            ss: ss as u64,
+            ..Context::default()
+        };
+        Self::with_state(TaskState::SavedContext(ctx), None)
+    }
+
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.status
+    }
+
+    fn in_kernel(&self) -> bool {
+        // preempted in a syscall, it might be holding kernel locks
+        match &self.state {
+            TaskState::SavedContext(ctx) => ctx.cs & 3 == 0,
+            TaskState::StartingInfo(_, _) => false,
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        // memory that would be given back by killing this task's process (not counting its page tables)
+        self.task_pt.as_ref().map_or(0, |pt| unsafe { pt.lock().user_pages() * mem::FRAME_SIZE })
+    }
+
+    fn shares_space(&self, other: &Task) -> bool {
+        match (&self.task_pt, &other.task_pt) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn exit(&mut self, code: i64) -> Remains {
+        self.status = TaskStatus::Zombie(code);
+        Remains {
+            space: self.task_pt.take(),
+            kernel_stack: core::mem::replace(&mut self.kernel_stack, KernelStack(Vec::new())),
         }
     }
 }
 
 impl Display for Task {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        unsafe {
-            write!(
-                f,
-                "PT: {}, Context: {:x?}",
-                self.task_pt.phys_addr(),
-                self.state
-            )
+        write!(
+            f,
+            "PID: {} (of {}), PT: {:?}, Status: {:?}, CPU: {:?}, Nice: {}, Context: {:x?}",
+            self.pid,
+            self.group,
+            self.task_pt.as_ref().map(|pt| pt.lock().phys_addr()),
+            self.status,
+            self.on_cpu,
+            self.sched.nice,
+            self.state
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct WaitQueue {
+    waiters: Mutex<Vec<usize>>, // pids of the blocked tasks
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn wait_event<T>(&self, mut poll: impl FnMut() -> Option<T>) -> T {
+        // Block the current task until poll returns something. It's polled again after every
+        // wake_all. Interrupts are off between polling and blocking so that a wake up can't get lost.
+        loop {
+            let res = without_interrupts(|| {
+                let res = poll();
+                if res.is_none() {
+                    if let Some(pid) = SCHEDULER.block_current() {
+                        self.waiters.lock().push(pid);
+                    }
+                }
+                res
+            });
+            match res {
+                Some(res) => return res,
+                None if SCHEDULER.current_pid().is_some() => yield_now(),
+                None => core::hint::spin_loop(), // no task to block (ie. still booting)
+            }
         }
     }
+
+    pub fn wake_all(&self) {
+        // called by interrupt handlers too, the waiters lock is only ever held with interrupts off
+        let pids = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        for pid in pids {
+            SCHEDULER.wake(pid);
+        }
+    }
+}
+
+pub static CHILD_EXITED: WaitQueue = WaitQueue::new(); // woken whenever a task exits
+
+struct CpuQueue {
+    policy: Box<dyn SchedPolicy>,    // has the runnable tasks waiting for this CPU and picks the next one
+    dead_stack: Option<KernelStack>, // of a task that exited here, freed once we're off it
 }
 
+// Every CPU runs the tasks in its own run queue, in the order its policy decides, and takes some
+// from the others when it's empty.
+// The scheduler's locks are only ever taken with interrupts off (interrupt handlers, or
+// without_interrupts in the rest of the kernel) so that an interrupt can't spin on them, and the
+// tasks lock is always taken before a run queue's.
 pub struct Scheduler {
     tasks: Mutex<Vec<Task>>,
-    cur_task: Mutex<Option<usize>>,
+    cpus: Vec<Mutex<CpuQueue>>, // indexed by smp::cpu_id()
 }
 
 impl Scheduler {
     pub fn new() -> Scheduler {
         Scheduler {
             tasks: Mutex::new(Vec::new()),
-            cur_task: Mutex::new(None), // so that next task is 0
-        }
-    }
-
-    pub unsafe fn schedule_data(&self, prog_data: Vec<u8>, entry_offset: usize) {
-        let prog_bytes = Pin::new(prog_data.into_boxed_slice());
-        let fn_addr = mem::VirtAddr::new(prog_bytes.as_ptr() as usize);
-        let userspace_fn_phys = fn_addr.to_phys().unwrap().0; // virtual address to physical
-        let page_phys_start = (userspace_fn_phys.addr() >> 12) << 12; // zero out page offset to get which page we should map
-        let fn_page_offset = userspace_fn_phys.addr() - page_phys_start; // offset of function from page start
-        let userspace_fn_virt_base = 0x400000; // target virtual address of page
-        let userspace_fn_virt = userspace_fn_virt_base + fn_page_offset + entry_offset; // target virtual address of function
-        serial_println!(
-            "Mapping {:x} to {:x}",
-            page_phys_start,
-            userspace_fn_virt_base
-        );
-        let mut task_pt = mem::PageTable::new(); // copy over the kernel's page tables
-        task_pt.map_virt_to_phys(
-            mem::VirtAddr::new(userspace_fn_virt_base),
-            mem::PhysAddr::new(page_phys_start),
-            mem::BIT_PRESENT | mem::BIT_USER,
-        ); // map the program's code
-        task_pt.map_virt_to_phys(
-            mem::VirtAddr::new(userspace_fn_virt_base).offset(mem::FRAME_SIZE),
-            mem::PhysAddr::new(page_phys_start).offset(mem::FRAME_SIZE),
-            mem::BIT_PRESENT | mem::BIT_USER,
-        ); // also map another page to be sure we got the entire function in
-        let mut stack_space = Pin::new(Box::new([0u8; mem::FRAME_SIZE])); // allocate some pinned memory to use for the stack
-        let stack_space_phys = mem::VirtAddr::new(stack_space.as_mut_ptr() as *const u8 as usize)
-            .to_phys()
-            .unwrap()
-            .0;
-        // take physical address of stack
-        task_pt.map_virt_to_phys(
-            mem::VirtAddr::new(0x800000),
-            stack_space_phys,
-            mem::BIT_PRESENT | mem::BIT_WRITABLE | mem::BIT_USER,
-        ); // map the stack memory to 0x800000
+            cpus: (0..smp::MAX_CPUS)
+                .map(|_| Mutex::new(CpuQueue { policy: PolicyKind::Fair.create(), dead_stack: None }))
+                .collect(),
+        }
+    }
+
+    pub fn set_policy(&self, kind: PolicyKind) -> Result<&'static str, mem::OutOfMemory> {
+        // switch every CPU to a new policy, moving the tasks waiting over, returns its name
+        without_interrupts(|| {
+            let mut tasks = self.tasks.lock();
+            let mut name = "";
+            for cpu in self.cpus.iter() {
+                let mut cpu = cpu.lock();
+                let mut policy = kind.create();
+                policy.reserve(tasks.len())?; // the CPUs switched so far keep the new one
+                while let Some(pid) = cpu.policy.pick_next() {
+                    if let Some(task) = tasks.iter_mut().find(|task| task.pid == pid) {
+                        policy.enqueue(pid, &mut task.sched);
+                    }
+                }
+                name = policy.name();
+                cpu.policy = policy;
+            }
+            Ok(name)
+        })
+    }
+
+    pub unsafe fn schedule_data(&self, prog_data: Vec<u8>, entry_offset: usize) -> Result<(), mem::OutOfMemory> {
+        let userspace_fn_virt_base = 0x400000; // target virtual address of the program
+        let mut task_pt = mem::AddressSpace::try_new()?; // copy over the kernel's page tables
+        for (i, chunk) in prog_data.chunks(mem::FRAME_SIZE).enumerate() {
+            // copy the program's code to new pages
+            let page = mem::VirtAddr::new(userspace_fn_virt_base).offset(i * mem::FRAME_SIZE);
+            let frame = task_pt.map_new_page(page, mem::BIT_PRESENT | mem::BIT_USER)?;
+            serial_println!("Mapping {:x} to {:x}", frame.addr(), page.addr());
+            let dst = frame.to_virt().unwrap().addr() as *mut u8;
+            core::ptr::copy_nonoverlapping(chunk.as_ptr(), dst, chunk.len());
+        }
+        let stack_end = task_pt.map_stack(mem::DEFAULT_STACK_SIZE, mem::DEFAULT_STACK_LIMIT)?; // map the stack memory
         let task = Task::new(
-            mem::VirtAddr::new(userspace_fn_virt),
-            mem::VirtAddr::new(0x801000),
+            mem::VirtAddr::new(userspace_fn_virt_base + entry_offset),
+            stack_end,
             task_pt,
-            prog_bytes,
-            stack_space,
-        ); // create task struct
-        self.schedule_task(task); // schedule the task
+        )?; // create task struct
+        self.schedule_task(task) // schedule the task
     }
 
+    pub fn schedule_task(&self, task: Task) -> Result<(), mem::OutOfMemory> {
+        // add the task to the run queue of the CPU with the fewest tasks waiting
+        let mut task = Some(task);
+        let res = without_interrupts(|| {
+            let mut tasks = self.tasks.lock();
+            tasks.try_reserve(1).map_err(|_| mem::OutOfMemory)?;
+            for cpu in self.cpus[..smp::cpu_count()].iter() {
+                // any run queue can hold all the tasks, so that waking one up never allocates
+                cpu.lock().policy.reserve(tasks.len() + 1)?;
+            }
+            let cpu = (0..smp::cpu_count())
+                .min_by_key(|cpu| self.cpus[*cpu].lock().policy.len())
+                .unwrap_or(0);
+            tasks.extend(task.take()); // push task struct to list of tasks
+            let task = tasks.last_mut().unwrap();
+            task.last_cpu = cpu;
+            self.cpus[cpu].lock().policy.enqueue(task.pid, &mut task.sched);
+            Ok(())
+        });
+        drop(task); // if there was no room for it, freed without the locks held
+        res
+    }
 
-    pub fn schedule_task(&self, task: Task) {
-        self.tasks.lock().push(task); // push task struct to list of tasks
+    pub fn spawn_kernel_thread(&self, f: impl FnOnce() + Send + 'static) -> Result<usize, mem::OutOfMemory> {
+        // start f in a kernel thread, returns its pid
+        let task = Task::kernel_thread(f)?;
+        let pid = task.pid;
+        serial_println!("Starting kernel thread {}", pid);
+        self.schedule_task(task)?;
+        Ok(pid)
     }
 
-    pub unsafe fn save_current_context(&self, ctxp: *const Context) {
-        self.cur_task.lock().map(|cur_task_idx| {
-            // if there is a current task
-            let ctx = (*ctxp).clone();
-            self.tasks.lock()[cur_task_idx].state = TaskState::SavedContext(ctx);
-            // replace its context with the given one
+    pub fn spawn_thread(&self, entry: u64, stack_top: u64, arg: u64) -> Option<Result<usize, mem::OutOfMemory>> {
+        // Start a thread of the current process running entry(arg) on the given stack, returns its
+        // thread id (a pid from the same counter). None if the current task has no user space.
+        let mut thread = match Task::user_thread(entry, stack_top, arg) {
+            Ok(thread) => thread,
+            Err(e) => return Some(Err(e)),
+        };
+        without_interrupts(|| {
+            let tasks = self.tasks.lock();
+            let cur = &tasks[Self::current_idx(&tasks)?];
+            thread.task_pt = Some(cur.task_pt.as_ref()?.clone());
+            thread.group = cur.group;
+            thread.sched.nice = cur.sched.nice;
+            Some(())
+        })?;
+        let tid = thread.pid;
+        Some(self.schedule_task(thread).map(|()| tid))
+    }
+
+    pub fn spawn_child(&self, mut task: Task) -> Result<usize, mem::OutOfMemory> {
+        // schedule a task spawned by the current one, which can wait for it (and whose nice value
+        // it starts with)
+        let (parent, nice) = without_interrupts(|| {
+            let tasks = self.tasks.lock();
+            Self::current_idx(&tasks).map_or((0, 0), |idx| (tasks[idx].pid, tasks[idx].sched.nice))
         });
+        task.parent = parent;
+        task.sched.nice = nice;
+        let pid = task.pid;
+        self.schedule_task(task)?;
+        Ok(pid)
+    }
+
+    fn current_idx(tasks: &[Task]) -> Option<usize> {
+        // the task running on this CPU
+        let cpu = smp::cpu_id();
+        tasks.iter().position(|task| task.on_cpu == Some(cpu))
+    }
+
+    pub fn current_pid(&self) -> Option<usize> {
+        without_interrupts(|| {
+            let tasks = self.tasks.lock();
+            Self::current_idx(&tasks).map(|idx| tasks[idx].pid)
+        })
+    }
+
+    fn enqueue(&self, task: &mut Task) {
+        // put a task that became runnable back in a run queue, unless it's still on its CPU (which
+        // queues it when it switches away from it)
+        if task.on_cpu.is_none() {
+            self.cpus[task.last_cpu].lock().policy.enqueue(task.pid, &mut task.sched);
+        }
+    }
+
+    pub fn set_nice(&self, pid: usize, nice: i64) -> Option<()> {
+        // change the nice value of the current task (pid 0 or its own) or of one of its children,
+        // None if there's no such task
+        without_interrupts(|| {
+            let mut tasks = self.tasks.lock();
+            let cur_pid = tasks[Self::current_idx(&tasks)?].pid;
+            let pid = if pid == 0 { cur_pid } else { pid };
+            let task = tasks
+                .iter_mut()
+                .find(|task| task.pid == pid && (pid == cur_pid || task.parent == cur_pid))?;
+            task.sched.nice = nice.clamp(sched_policy::NICE_MIN as i64, sched_policy::NICE_MAX as i64) as i8;
+            Some(()) // a task already in a run queue keeps its place there until its next turn
+        })
+    }
+
+    fn exit_task(&self, tasks: &mut Vec<Task>, idx: usize, code: i64) -> Remains {
+        // Turn the task into a zombie. Its children are adopted by the kernel, which never waits
+        // for them, so zombies without a parent are removed right away (maybe this one too).
+        // Threads have no parent but can be joined by the rest of their process, so they're kept
+        // until its last thread exits.
+        let (pid, group) = (tasks[idx].pid, tasks[idx].group);
+        let remains = tasks[idx].exit(code);
+        for cpu in self.cpus.iter() {
+            cpu.lock().policy.remove(pid); // if it was killed while waiting
+        }
+        for task in tasks.iter_mut().filter(|task| task.parent == pid) {
+            task.parent = 0;
+        }
+        let group_alive = tasks.iter().any(|task| task.group == group && task.task_pt.is_some());
+        tasks.retain(|task| {
+            let joinable = task.pid != task.group && (task.group != group || group_alive);
+            task.parent != 0 || joinable || !matches!(task.status, TaskStatus::Zombie(_))
+        });
+        remains
+    }
+
+    fn kill_largest_task(&self) -> Option<(usize, usize, Remains)> {
+        // Kill the process using the most memory (all of its threads), returning what has to be
+        // dropped to free it. Processes with a thread running on a CPU are never picked (the
+        // current one as we're running on its behalf and on its page table), nor are those with a
+        // thread that was preempted (or is blocked) in the kernel.
+        let mut tasks = self.tasks.lock(); // page faults never happen with it held
+        let busy = |task: &Task| task.on_cpu.is_some() || task.in_kernel();
+        let (idx, size) = tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, task)| task.task_pt.is_some())
+            .filter(|(_, task)| !tasks.iter().any(|other| other.shares_space(task) && busy(other)))
+            .map(|(i, task)| (i, task.memory_size()))
+            .max_by_key(|(_, size)| *size)?;
+        let (pid, group) = (tasks[idx].pid, tasks[idx].group);
+        // the other threads first, their remains don't hold the last reference to the memory
+        let other_thread = |task: &Task| task.group == group && task.pid != pid && task.task_pt.is_some();
+        while let Some(other) = tasks.iter().position(other_thread) {
+            drop(self.exit_task(&mut tasks, other, -1));
+        }
+        let idx = tasks.iter().position(|task| task.pid == pid)?; // exit_task moves them around
+        Some((pid, size, self.exit_task(&mut tasks, idx, -1)))
+    }
+
+    fn sharing_cpus(tasks: &[Task], idx: usize) -> u32 {
+        // the other CPUs running a thread of the same process as the given task, which might have
+        // some of its pages in their TLBs
+        tasks
+            .iter()
+            .filter(|task| task.pid != tasks[idx].pid && task.shares_space(&tasks[idx]))
+            .filter_map(|task| task.on_cpu)
+            .fold(0, |cpus, cpu| cpus | 1 << cpu)
+    }
+
+    /// # Safety
+    /// Only from the page fault handler, with interrupts off.
+    pub unsafe fn fault_in_current(&self, addr: usize, write: bool) -> Option<(usize, mem::PageFault, Unmapped)> {
+        // Let the current task's stack or mapped files handle a fault at addr, along with the
+        // task's pid and the page it replaced if it was copied on write (the lock is never held
+        // while touching user memory, but another CPU might have it).
+        let tasks = self.tasks.lock();
+        let idx = Self::current_idx(&tasks)?;
+        let task = &tasks[idx];
+        let mut space = task.task_pt.as_ref()?.lock();
+        let fault = space.grow_stack(addr).or_else(|| mmap::handle_fault(&mut space, addr, write));
+        let unmapped = Unmapped {
+            cpus: Self::sharing_cpus(&tasks, idx),
+            frames: space.take_unmapped(),
+        };
+        fault.map(|fault| (task.pid, fault, unmapped))
     }
 
-    pub unsafe fn run_next(&self) {
-        let tasks_len = self.tasks.lock().len(); // how many tasks are available
-        if tasks_len > 0 {
-            let task_state = {
-                let mut cur_task_opt = self.cur_task.lock(); // lock the current task index
-                let cur_task = cur_task_opt.get_or_insert(0); // default to 0
-                let next_task = (*cur_task + 1) % tasks_len; // next task index
-                *cur_task = next_task;
-                let task = &self.tasks.lock()[next_task]; // get the next task
-                serial_println!("Switching to task #{} ({})", next_task, task);
-                task.task_pt.enable(); // enable task's page table
-                task.state.clone() // clone task state information
-            }; // release held locks
-            match task_state {
-                TaskState::SavedContext(ctx) => {
+    pub fn with_current_space<R>(&self, f: impl FnOnce(&mut mem::AddressSpace) -> R) -> Option<R> {
+        // Run f on the address space of the current task (ie. from a syscall). Interrupts are off
+        // meanwhile or a context switch could spin on the lock. It might have changed or unmapped
+        // pages so the other CPUs running its threads flush their TLBs afterwards, and only then
+        // are the unmapped frames freed.
+        let (res, unmapped) = without_interrupts(|| {
+            let tasks = self.tasks.lock();
+            let idx = Self::current_idx(&tasks)?;
+            let mut space = tasks[idx].task_pt.as_ref()?.lock();
+            let res = f(&mut space);
+            let unmapped = Unmapped {
+                cpus: Self::sharing_cpus(&tasks, idx),
+                frames: space.take_unmapped(),
+            };
+            Some((res, unmapped))
+        })?;
+        smp::tlb_shootdown(unmapped.cpus);
+        unmapped.frames.into_iter().for_each(frame_alloc::free_frame);
+        Some(res)
+    }
+
+    pub fn block_current(&self) -> Option<usize> {
+        // mark the current task as blocked (it keeps running until it yields), returns its pid
+        let mut tasks = self.tasks.lock();
+        let idx = Self::current_idx(&tasks)?;
+        tasks[idx].status = TaskStatus::Blocked;
+        Some(tasks[idx].pid)
+    }
+
+    pub fn wake(&self, pid: usize) {
+        without_interrupts(|| {
+            let mut tasks = self.tasks.lock();
+            if let Some(task) = tasks.iter_mut().find(|task| task.pid == pid) {
+                if task.status == TaskStatus::Blocked {
+                    task.status = TaskStatus::Runnable;
+                    self.enqueue(task);
+                }
+            }
+        })
+    }
+
+    fn sleep_current(&self, until: u64) -> Result<(), mem::OutOfMemory> {
+        // mark the current task as sleeping until the given tick (it keeps running until it yields)
+        let mut queue = TIMER_QUEUE.lock();
+        queue.try_reserve(1).map_err(|_| mem::OutOfMemory)?;
+        let mut tasks = self.tasks.lock();
+        let task = match Self::current_idx(&tasks) {
+            Some(idx) => &mut tasks[idx],
+            None => return Ok(()),
+        };
+        task.status = TaskStatus::Sleeping(until);
+        queue.push(Reverse((until, task.pid)));
+        Ok(())
+    }
+
+    fn wake_sleepers(&self, now: u64) {
+        // called by the timer, makes the tasks whose time has come runnable again
+        let mut queue = TIMER_QUEUE.lock();
+        let mut tasks = self.tasks.lock();
+        while let Some(Reverse((until, pid))) = queue.peek().copied() {
+            if until > now {
+                break;
+            }
+            queue.pop();
+            if let Some(task) = tasks.iter_mut().find(|task| task.pid == pid) {
+                if task.status == TaskStatus::Sleeping(until) {
+                    task.status = TaskStatus::Runnable;
+                    self.enqueue(task);
+                }
+            }
+        }
+    }
+
+    pub fn reap_child(&self, pid: usize) -> Option<Result<i64, ()>> {
+        // Remove the given child of the current task if it has exited and return its exit code.
+        // None if it's still running, Err if it's not a child of the current task.
+        without_interrupts(|| {
+            let mut tasks = self.tasks.lock();
+            let parent = tasks[Self::current_idx(&tasks)?].pid;
+            let idx = match tasks.iter().position(|task| task.pid == pid && task.parent == parent) {
+                Some(idx) => idx,
+                None => return Some(Err(())),
+            };
+            match tasks[idx].status {
+                TaskStatus::Zombie(code) => {
+                    tasks.remove(idx);
+                    Some(Ok(code))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    pub fn reap_thread(&self, tid: usize) -> Option<Result<i64, ()>> {
+        // Same as reap_child but for another thread of the current process (not its first one,
+        // that's the parent's to wait for).
+        without_interrupts(|| {
+            let mut tasks = self.tasks.lock();
+            let cur = &tasks[Self::current_idx(&tasks)?];
+            let (pid, group) = (cur.pid, cur.group);
+            let found = tasks.iter().position(|task| task.pid == tid && task.group == group && tid != group && tid != pid);
+            let idx = match found {
+                Some(idx) => idx,
+                None => return Some(Err(())),
+            };
+            match tasks[idx].status {
+                TaskStatus::Zombie(code) => {
+                    tasks.remove(idx);
+                    Some(Ok(code))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// # Safety
+    /// Only from a syscall of the current task.
+    pub unsafe fn set_fs_base(&self, addr: u64) {
+        // for thread local storage, it's switched along with the task
+        without_interrupts(|| {
+            let mut tasks = self.tasks.lock();
+            if let Some(idx) = Self::current_idx(&tasks) {
+                tasks[idx].fs_base = addr;
+                Msr::new(MSR_FS_BASE).write(addr);
+            }
+        })
+    }
+
+    pub fn fs_base(&self) -> u64 {
+        without_interrupts(|| {
+            let tasks = self.tasks.lock();
+            Self::current_idx(&tasks).map_or(0, |idx| tasks[idx].fs_base)
+        })
+    }
+
+    /// # Safety
+    /// Only from a syscall or fault of the current task, with interrupts off.
+    pub unsafe fn exit_current(&self, code: i64) -> ! {
+        // Exit the current task, freeing its memory, and run the next one. We're still running
+        // on its kernel stack so that one is only freed on the idle stack.
+        asm!("cli");
+        let remains = {
+            let mut tasks = self.tasks.lock();
+            Self::current_idx(&tasks).map(|idx| self.exit_task(&mut tasks, idx, code))
+        };
+        if let Some(remains) = remains {
+            mem::enable_kernel_space(); // the last thread of the process might free its page table anytime
+            drop(remains.space);
+            self.cpus[smp::cpu_id()].lock().dead_stack = Some(remains.kernel_stack);
+            CHILD_EXITED.wake_all();
+        }
+        enter_idle()
+    }
+
+    fn release_current(&self) {
+        // Called on the idle stack once this CPU stopped running its task: it can be picked by
+        // another CPU now (and goes back in the run queue if it's still runnable), and the stack
+        // of a task that exited here can be freed.
+        let dead_stack = {
+            let mut tasks = self.tasks.lock();
+            let mut cpu = self.cpus[smp::cpu_id()].lock();
+            if let Some(idx) = Self::current_idx(&tasks) {
+                let task = &mut tasks[idx];
+                task.on_cpu = None;
+                cpu.policy.ran(&mut task.sched, time::uptime_ns().saturating_sub(task.run_start));
+                if task.status == TaskStatus::Runnable {
+                    cpu.policy.enqueue(task.pid, &mut task.sched);
+                }
+            }
+            cpu.dead_stack.take()
+        };
+        drop(dead_stack); // outside of the locks
+    }
+
+    pub unsafe fn save_current_context(&self, ctxp: *const Context) {
+        let mut tasks = self.tasks.lock();
+        if let Some(idx) = Self::current_idx(&tasks) {
+            // if there is a current task replace its context with the given one
+            tasks[idx].state = TaskState::SavedContext((*ctxp).clone());
+            tasks[idx].fpu.save(); // still the task's, nothing in the kernel uses them
+        }
+    }
+
+    fn steal(&self, cpu: usize) -> Option<usize> {
+        // take the next task waiting for another CPU, for when this one has nothing to do
+        let count = smp::cpu_count();
+        (1..count)
+            .map(|i| (cpu + i) % count)
+            .find_map(|other| self.cpus[other].lock().policy.pick_next())
+    }
+
+    unsafe fn switch_to_next(&self) -> Option<TaskState> {
+        // pick the next task waiting for this CPU and switch to its page table and stack
+        let cpu = smp::cpu_id();
+        let mut tasks = self.tasks.lock();
+        loop {
+            let queued = self.cpus[cpu].lock().policy.pick_next(); // one run queue lock at a time
+            let pid = queued.or_else(|| self.steal(cpu))?;
+            let task = match tasks.iter_mut().find(|task| task.pid == pid) {
+                Some(task) if task.status == TaskStatus::Runnable && task.on_cpu.is_none() => task,
+                _ => continue,
+            };
+            task.on_cpu = Some(cpu);
+            task.last_cpu = cpu;
+            task.run_start = time::uptime_ns();
+            serial_println!("CPU {}: switching to task {}", cpu, task);
+            if let Some(pt) = &task.task_pt {
+                pt.lock().enable(); // enable task's page table, kernel threads stay on the kernel's
+            }
+            task.fpu.restore(); // and its floating point registers, the kernel leaves them alone until then
+            Msr::new(MSR_FS_BASE).write(task.fs_base); // and its thread local storage
+            gdt::set_kernel_stack(task.kernel_stack.end()); // and its stack for syscalls and interrupts
+            return Some(task.state.clone()); // clone task state information
+        }
+    }
+
+    pub unsafe fn run_next(&self) -> ! {
+        // called on this CPU's idle stack with interrupts off
+        loop {
+            match self.switch_to_next() {
+                Some(TaskState::SavedContext(ctx)) => {
                     restore_context(&ctx) // either restore the saved context
                 }
-                TaskState::StartingInfo(exec_base, stack_end) => {
+                Some(TaskState::StartingInfo(exec_base, stack_end)) => {
                     jmp_to_usermode(exec_base, stack_end) // or initialize the task with the given instruction, stack pointers
                 }
+                None => idle_task(smp::cpu_id()),
             }
         }
-        loop {} // no task to jump to
     }
 }
 
+unsafe fn idle_task(cpu: usize) {
+    // This CPU's idle task, which only runs when nothing else can: halt with interrupts on until
+    // one might have woken a task up. The timer doesn't come back here but starts over on the idle
+    // stack, which ends the idle time there.
+    IDLE_SINCE[cpu].store(time::uptime_ns(), Ordering::SeqCst);
+    asm!("sti; hlt; cli");
+    end_idle(cpu);
+}
+
+fn end_idle(cpu: usize) {
+    let since = IDLE_SINCE[cpu].swap(NOT_IDLE, Ordering::SeqCst);
+    if since != NOT_IDLE {
+        IDLE_NS[cpu].fetch_add(time::uptime_ns().saturating_sub(since), Ordering::SeqCst);
+    }
+}
+
+pub fn idle_ns(cpu: usize) -> u64 {
+    // how long the CPU had nothing to run, its utilization is the rest of the uptime
+    let since = IDLE_SINCE[cpu].load(Ordering::SeqCst);
+    let current = if since != NOT_IDLE { time::uptime_ns().saturating_sub(since) } else { 0 };
+    IDLE_NS[cpu].load(Ordering::SeqCst) + current
+}
+
 lazy_static! {
     pub static ref SCHEDULER: Scheduler = Scheduler::new();
 }
 
+pub fn oom_kill() -> bool {
+    // Called when a page fault finds no free frame for a user page, returns whether any memory was
+    // freed. Fallible allocations (syscall buffers, new tasks...) just fail with ENOMEM instead, so
+    // that a single oversized request doesn't take the other tasks down.
+    match SCHEDULER.kill_largest_task() {
+        Some((pid, size, remains)) => {
+            drop(remains); // outside of the scheduler's locks, freeing its memory
+            CHILD_EXITED.wake_all();
+            serial_println!("Out of memory: killed task {} ({} bytes)", pid, size);
+            println!("Out of memory: killed task {}", pid);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn can_block() -> bool {
+    // whether the caller can wait in a WaitQueue: it's a task running with interrupts enabled
+    x86_64::instructions::interrupts::are_enabled() && SCHEDULER.current_pid().is_some()
+}
+
+pub fn sleep_until(deadline: u64) -> Result<(), mem::OutOfMemory> {
+    // park the current task until the tick counter reaches deadline, the timer wakes it
+    while time::ticks() < deadline {
+        if SCHEDULER.current_pid().is_none() {
+            core::hint::spin_loop(); // no task to park (ie. still booting)
+            continue;
+        }
+        without_interrupts(|| SCHEDULER.sleep_current(deadline))?;
+        yield_now();
+    }
+    Ok(())
+}
+
+/// # Safety
+/// Only from the page fault handler, with interrupts off.
+pub unsafe fn handle_page_fault(addr: usize, write: bool, from_user: bool, interrupts_on: bool) -> bool {
+    // Called on page faults to grow the current task's stack or read in a page of a mapped file,
+    // returns whether the fault was handled. A task that overflows its stack (or that there's no
+    // memory for) from user space is killed and never returns here.
+    loop {
+        let (pid, fault, unmapped) = match SCHEDULER.fault_in_current(addr, write) {
+            Some(res) => res,
+            None => return false, // a real fault
+        };
+        unmapped.release(); // the other threads could still be reading a page that was copied on write
+        let fault = match fault {
+            mem::PageFault::NeedsPage(inode, page) => {
+                // read it with the scheduler's locks released, waiting for the disk if the
+                // faulting code could be preempted anyway
+                if interrupts_on {
+                    asm!("sti");
+                }
+                let res = mmap::read_page(inode, page);
+                asm!("cli");
+                match res {
+                    Ok(()) => continue,
+                    Err(mem::OutOfMemory) => mem::PageFault::OutOfMemory,
+                }
+            }
+            fault => fault,
+        };
+        match fault {
+            mem::PageFault::Handled => return true,
+            mem::PageFault::OutOfMemory if oom_kill() => continue, // retry with the freed memory
+            _ if !from_user => return false, // a kernel copy that will fail by itself
+            mem::PageFault::StackOverflow => {
+                serial_println!("Stack overflow in pid {} at {:x}", pid, addr);
+                println!("stack overflow in pid {}", pid);
+            }
+            _ => {
+                serial_println!("Out of memory on a page fault of pid {}", pid);
+                println!("Out of memory: killed task {}", pid);
+            }
+        }
+        SCHEDULER.exit_current(-1); // frees its memory and runs the next task
+    }
+}
+
+extern "C" fn kthread_entry(f: *mut Box<dyn FnOnce() + Send>) -> ! {
+    // where kernel threads start, with the closure given to Task::kernel_thread
+    let f = unsafe { Box::from_raw(f) };
+    f();
+    unsafe { SCHEDULER.exit_current(0) }
+}
+
+pub fn log_stats(interval_ms: u64) {
+    // a kernel thread that logs how busy the CPUs are to the serial port every so often
+    loop {
+        let deadline = time::ticks().saturating_add(time::ns_to_ticks(interval_ms.saturating_mul(1_000_000)));
+        if sleep_until(deadline).is_err() {
+            continue; // no memory for the timer queue, try again
+        }
+        let uptime = time::uptime_ns().max(1);
+        let tasks = without_interrupts(|| SCHEDULER.tasks.lock().len());
+        serial_println!("Stats: up {} ms, {} tasks", uptime / 1_000_000, tasks);
+        for cpu in 0..smp::cpu_count() {
+            serial_println!("Stats: CPU {} busy {}%", cpu, 100 - (idle_ns(cpu) / (uptime / 100).max(1)).min(100));
+        }
+    }
+}
+
+unsafe extern "C" fn idle_entry() -> ! {
+    // Runs on this CPU's idle stack, which nothing else uses. The task that was running can go
+    // to another CPU from here on, or have its page table freed, so we leave that first.
+    end_idle(smp::cpu_id()); // if we got here by interrupting the idle task
+    mem::enable_kernel_space();
+    SCHEDULER.release_current();
+    SCHEDULER.run_next()
+}
+
+/// # Safety
+/// Only once per CPU at the end of its boot, with everything it needs set up.
+pub unsafe fn enter_idle() -> ! {
+    // leave the stack we're on (the current task's or the boot stack) and run the next task, or
+    // the idle task if there's none
+    asm!("mov rsp, {}; call {}", in(reg) smp::current().idle_stack_end, sym idle_entry, options(noreturn));
+}
+
 pub unsafe extern "sysv64" fn context_switch(ctx: *const Context) {
-    SCHEDULER.save_current_context(ctx);
     port::end_of_interrupt(32);
-    SCHEDULER.run_next();
-}
\ No newline at end of file
+    apic::rearm_timer(); // every CPU has its own timer
+    let cpu = smp::cpu_id();
+    if cpu == 0 {
+        let now = time::tick(); // but only the BSP counts the ticks
+        SCHEDULER.wake_sleepers(now);
+    }
+    if IDLE_SINCE[cpu].load(Ordering::SeqCst) == NOT_IDLE && SCHEDULER.current_pid().is_none() {
+        restore_context(&*ctx); // neither a task nor the idle task, the kernel's still booting
+    }
+    SCHEDULER.save_current_context(ctx);
+    enter_idle();
+}
+
+/// # Safety
+/// Only from the yield interrupt, with ctx pointing to the context it saved.
+pub unsafe extern "sysv64" fn yield_switch(ctx: *const Context) {
+    // same as the timer's context switch but there's no interrupt to acknowledge
+    SCHEDULER.save_current_context(ctx);
+    enter_idle();
+}
+
+pub fn yield_now() {
+    // give up the CPU from the kernel (ie. in a syscall), the task resumes here on its next turn
+    unsafe {
+        asm!("int {}", const YIELD_VECTOR);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::frame_alloc;
+use crate::mem::{self, AddressSpace, PhysAddr, VirtAddr, FRAME_SIZE};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+// Named shared memory objects. An object is a set of frames that any task can map into its
+// address space. The object holds a reference to each of its frames and every mapping takes
+// another one, so unlinking an object only removes its name: the memory stays around until the
+// last task unmaps it (or exits).
+// OBJECTS is also taken by shm::map with the scheduler's locks held, so it's only ever locked with
+// interrupts off: a task preempted while holding it would leave that CPU spinning forever.
+
+pub const MAX_NAME_LEN: usize = 64;
+const MAX_SIZE: usize = 16 << 20;
+const PAGE_OPTIONS: u64 =
+    mem::BIT_PRESENT | mem::BIT_WRITABLE | mem::BIT_USER | mem::BIT_NO_EXECUTE | mem::BIT_SHARED;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    Exists,      // an object with that name already exists
+    NotFound,    // no object with that name / id
+    InvalidSize, // size is 0 or too big
+    BadAddress,  // the mapping is not page aligned, not in user space or overlaps existing pages
+    OutOfMemory,
+}
+
+impl From<mem::OutOfMemory> for ShmError {
+    fn from(_: mem::OutOfMemory) -> Self {
+        ShmError::OutOfMemory
+    }
+}
+
+struct ShmObject {
+    id: usize,
+    name: String,
+    frames: Vec<PhysAddr>,
+}
+
+static OBJECTS: Mutex<Vec<ShmObject>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+pub fn create(name: &str, size: usize) -> Result<usize, ShmError> {
+    // create a new zeroed object of (at least) size bytes and return its id
+    if size == 0 || size > MAX_SIZE {
+        return Err(ShmError::InvalidSize);
+    }
+    if open(name).is_ok() {
+        return Err(ShmError::Exists); // don't bother allocating, it's checked again below
+    }
+    let pages = size.div_ceil(FRAME_SIZE);
+    let mut frames = Vec::new();
+    let mut obj_name = String::new();
+    if frames.try_reserve_exact(pages).is_err() || obj_name.try_reserve_exact(name.len()).is_err() {
+        return Err(ShmError::OutOfMemory);
+    }
+    obj_name.push_str(name);
+    for _ in 0..pages {
+        match frame_alloc::alloc_zeroed_frame() {
+            Some(frame) => frames.push(frame),
+            None => {
+                frames.into_iter().for_each(frame_alloc::free_frame);
+                return Err(ShmError::OutOfMemory);
+            }
+        }
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut obj = Some(ShmObject {
+        id,
+        name: obj_name,
+        frames,
+    });
+    let res = without_interrupts(|| {
+        let mut objects = OBJECTS.lock();
+        if objects.iter().any(|other| other.name == name) {
+            return Err(ShmError::Exists); // someone else created it meanwhile
+        }
+        objects.try_reserve(1).map_err(|_| ShmError::OutOfMemory)?;
+        objects.extend(obj.take());
+        Ok(id)
+    });
+    if let Some(obj) = obj {
+        obj.frames.into_iter().for_each(frame_alloc::free_frame); // it didn't make it in
+    }
+    res
+}
+
+pub fn open(name: &str) -> Result<usize, ShmError> {
+    without_interrupts(|| {
+        OBJECTS
+            .lock()
+            .iter()
+            .find(|obj| obj.name == name)
+            .map(|obj| obj.id)
+            .ok_or(ShmError::NotFound)
+    })
+}
+
+/// # Safety
+/// space must be the current task's address space, with interrupts off.
+pub unsafe fn map(space: &mut AddressSpace, id: usize, addr: usize) -> Result<usize, ShmError> {
+    // map the whole object at addr (or wherever there's room if it's 0) in the given address
+    // space, returns the address (called with interrupts off, from with_current_space)
+    let objects = OBJECTS.lock();
+    let obj = objects.iter().find(|obj| obj.id == id).ok_or(ShmError::NotFound)?;
+    let size = obj.frames.len() * FRAME_SIZE;
+    let addr = if addr == 0 {
+        crate::mmap::find_free_range(space, size).ok_or(ShmError::OutOfMemory)?
+    } else {
+        addr
+    };
+    if addr % FRAME_SIZE != 0
+        || crate::usercopy::validate_user_range(addr, size).is_err()
+        || crate::mmap::overlaps(space, addr, addr + size)
+    {
+        return Err(ShmError::BadAddress);
+    }
+    let pt = space.page_table();
+    if (addr..addr + size).step_by(FRAME_SIZE).any(|page| pt.get_mapping(VirtAddr::new(page)).is_some()) {
+        return Err(ShmError::BadAddress); // don't replace anything that's mapped already
+    }
+    space.reserve_unmapped(obj.frames.len())?; // so that undoing it can't fail
+    for (i, frame) in obj.frames.iter().enumerate() {
+        let page = VirtAddr::new(addr + i * FRAME_SIZE);
+        if let Err(e) = space.map_frame(page, *frame, PAGE_OPTIONS) {
+            let _ = unmap(space, addr, i * FRAME_SIZE); // undo the pages mapped so far
+            return Err(e.into());
+        }
+    }
+    Ok(addr)
+}
+
+/// # Safety
+/// space must be the current task's address space, with interrupts off.
+pub unsafe fn unmap(space: &mut AddressSpace, addr: usize, size: usize) -> Result<usize, ShmError> {
+    // unmap the shared pages in the range, other pages are left alone, returns how many were unmapped
+    let end = match addr.checked_add(size) {
+        Some(end) => end,
+        None => return Ok(0),
+    };
+    let pages = (addr / FRAME_SIZE * FRAME_SIZE..end).step_by(FRAME_SIZE).map(VirtAddr::new);
+    let shared =
+        |space: &AddressSpace, page: VirtAddr| space.page_table().get_mapping(page).is_some_and(|pte| pte.get_bit(mem::BIT_SHARED));
+    let count = pages.clone().filter(|page| shared(space, *page)).count();
+    space.reserve_unmapped(count)?;
+    for page in pages {
+        if shared(space, page) {
+            let _ = space.unmap_page(page); // there's room for it, reserved above
+        }
+    }
+    Ok(count)
+}
+
+pub fn unlink(name: &str) -> Result<(), ShmError> {
+    // remove the name, the frames are freed once nobody has them mapped anymore
+    let obj = without_interrupts(|| {
+        let mut objects = OBJECTS.lock();
+        let idx = objects.iter().position(|obj| obj.name == name)?;
+        Some(objects.remove(idx))
+    })
+    .ok_or(ShmError::NotFound)?;
+    obj.frames.into_iter().for_each(frame_alloc::free_frame);
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use crate::mem::FRAME_SIZE;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt::Display;
+use core::ptr::null_mut;
+use spin::Mutex;
+
+// Object sizes served by the slab allocator, anything larger goes to the buddy allocator. The free
+// list lives in the free objects themselves so there's no header, and the last class holds a single
+// object per page for everything between 2 KiB and a page.
+pub const SIZE_CLASSES: [usize; 9] = [16, 32, 64, 128, 256, 512, 1024, 2048, FRAME_SIZE];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats {
+    pub object_size: usize, // size of the objects of this class
+    pub pages: usize,       // pages carved into objects for this class
+    pub in_use: usize,      // objects currently handed out
+    pub free: usize,        // objects in the free list
+    pub allocs: usize,      // total allocations served
+    pub frees: usize,       // total deallocations
+}
+
+struct SizeClass {
+    free_list: usize, // virtual address of the first free object (0 if empty), each free object holds the next one
+    stats: SlabStats,
+}
+
+pub struct SlabAllocator {
+    classes: [Mutex<SizeClass>; SIZE_CLASSES.len()],
+}
+
+impl SizeClass {
+    const fn new(object_size: usize) -> SizeClass {
+        SizeClass {
+            free_list: 0,
+            stats: SlabStats {
+                object_size,
+                pages: 0,
+                in_use: 0,
+                free: 0,
+                allocs: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut u8> {
+        if self.free_list == 0 {
+            return None;
+        }
+        let obj = self.free_list as *mut usize;
+        self.free_list = *obj; // the next free object is stored in the first word of the free one
+        self.stats.free -= 1;
+        self.stats.in_use += 1;
+        self.stats.allocs += 1;
+        Some(obj as *mut u8)
+    }
+
+    unsafe fn push(&mut self, ptr: *mut u8) {
+        *(ptr as *mut usize) = self.free_list;
+        self.free_list = ptr as usize;
+        self.stats.free += 1;
+    }
+
+    unsafe fn add_page(&mut self, page: *mut u8) {
+        // carve a fresh page into objects and put them in the free list (in address order)
+        let size = self.stats.object_size;
+        for off in (0..FRAME_SIZE).step_by(size).rev() {
+            self.push(page.add(off));
+        }
+        self.stats.pages += 1;
+    }
+}
+
+impl SlabAllocator {
+    pub const fn new() -> SlabAllocator {
+        SlabAllocator {
+            classes: [
+                Mutex::new(SizeClass::new(SIZE_CLASSES[0])),
+                Mutex::new(SizeClass::new(SIZE_CLASSES[1])),
+                Mutex::new(SizeClass::new(SIZE_CLASSES[2])),
+                Mutex::new(SizeClass::new(SIZE_CLASSES[3])),
+                Mutex::new(SizeClass::new(SIZE_CLASSES[4])),
+                Mutex::new(SizeClass::new(SIZE_CLASSES[5])),
+                Mutex::new(SizeClass::new(SIZE_CLASSES[6])),
+                Mutex::new(SizeClass::new(SIZE_CLASSES[7])),
+                Mutex::new(SizeClass::new(SIZE_CLASSES[8])),
+            ],
+        }
+    }
+
+    fn class_index(layout: &Layout) -> Option<usize> {
+        // Objects are carved out of page-aligned pages at multiples of their (power of 2) size,
+        // so a class aligns its objects to its size. Pick the smallest class that fits both.
+        let size = core::cmp::max(layout.size(), layout.align());
+        SIZE_CLASSES.iter().position(|class_size| *class_size >= size)
+    }
+
+    pub fn serves(layout: &Layout) -> bool {
+        Self::class_index(layout).is_some()
+    }
+
+    /// # Safety
+    /// page_source must hand out page aligned pages that are never freed while the slab holds them.
+    pub unsafe fn alloc(&self, layout: Layout, page_source: &dyn GlobalAlloc) -> *mut u8 {
+        let idx = match Self::class_index(&layout) {
+            Some(idx) => idx,
+            None => return null_mut(),
+        };
+        if let Some(obj) = self.classes[idx].lock().pop() {
+            return obj;
+        }
+        // Out of objects: get a new page without holding the class lock, as the page allocator
+        // might need to allocate small objects itself (possibly from this very class).
+        let page = page_source.alloc(Layout::from_size_align_unchecked(FRAME_SIZE, FRAME_SIZE));
+        if page.is_null() {
+            return null_mut();
+        }
+        let mut class = self.classes[idx].lock();
+        class.add_page(page);
+        class.pop().unwrap_or(null_mut())
+    }
+
+    /// # Safety
+    /// ptr must have been allocated with the same layout and not be used anymore.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Pages are kept by their class once carved. A block of the right size that came from
+        // somewhere else (ie. allocated before the slab allocator was in use) is simply adopted.
+        if let Some(idx) = Self::class_index(&layout) {
+            let mut class = self.classes[idx].lock();
+            class.push(ptr);
+            class.stats.in_use = class.stats.in_use.saturating_sub(1);
+            class.stats.frees += 1;
+        }
+    }
+
+    pub fn stats(&self) -> [SlabStats; SIZE_CLASSES.len()] {
+        let mut stats = [SlabStats::default(); SIZE_CLASSES.len()];
+        for (i, class) in self.classes.iter().enumerate() {
+            stats[i] = class.lock().stats;
+        }
+        stats
+    }
+}
+
+impl Default for SlabAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for SlabAllocator {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for stats in self.stats().iter() {
+            writeln!(
+                f,
+                "  Slab {:>4}: {} pages / {} in use / {} free / {} allocs / {} frees",
+                stats.object_size, stats.pages, stats.in_use, stats.free, stats.allocs, stats.frees
+            )?;
+        }
+        Ok(())
+    }
+}
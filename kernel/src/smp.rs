@@ -0,0 +1,204 @@
+use crate::acpi::Madt;
+use crate::{apic, fpu, gdt, interrupts, mem, port, println, scheduler, serial_println, syscalls, usercopy};
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+
+// Multiprocessor support. The BSP starts the other CPUs (APs) listed in the MADT with the
+// INIT-SIPI-SIPI sequence, each one goes through the real mode trampoline in ap_trampoline.asm to
+// ap_start which sets up its GDT, TSS, local APIC etc. and starts running tasks.
+// Every CPU keeps its own data in a Cpu struct that GS points to while in the kernel: the syscall
+// entry and the interrupt handlers swapgs when coming from user space (and before going back).
+// The threads of a process can run on several CPUs at once, so changing their page table (other
+// than adding pages) means telling the other CPUs to flush their TLBs too.
+
+pub const MAX_CPUS: usize = 16;
+const AP_TRAMPOLINE: usize = 0x8000; // must match ap_trampoline.asm
+const IDLE_STACK_SIZE: usize = 0x4000;
+const MSR_GS_BASE: u32 = 0xC0000101;
+const MSR_KERNEL_GS_BASE: u32 = 0xC0000102;
+const MSR_EFER: u32 = 0xC0000080;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF0;
+
+// offsets in Cpu used by assembly
+pub const CPU_KERNEL_STACK_END: usize = 8;
+pub const CPU_USER_RSP: usize = 16;
+
+#[repr(C)]
+pub struct Cpu {
+    self_ptr: u64,             // gs:0, to get a normal pointer to it
+    pub kernel_stack_end: u64, // gs:8, the running task's kernel stack for the syscall entry
+    user_rsp: u64,             // gs:16, the syscall entry keeps the user stack here for a moment
+    pub id: usize,             // index in CPUS
+    pub lapic_id: u8,
+    pub idle_stack_end: u64, // the scheduler runs here between tasks
+}
+
+const EMPTY_CPU: Cpu = Cpu {
+    self_ptr: 0,
+    kernel_stack_end: 0,
+    user_rsp: 0,
+    id: 0,
+    lapic_id: 0,
+    idle_stack_end: 0,
+};
+
+static mut CPUS: [Cpu; MAX_CPUS] = [EMPTY_CPU; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1); // CPUs that are up
+static AP_READY: AtomicBool = AtomicBool::new(false); // the AP being started got to ap_start
+static SHOOTDOWN: Mutex<()> = Mutex::new(()); // one shootdown at a time
+static SHOOTDOWN_PENDING: AtomicU32 = AtomicU32::new(0); // CPUs that still have to flush their TLB
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// # Safety
+/// Only once per CPU at boot, with its own id.
+pub unsafe fn init_cpu(id: usize) {
+    // point GS to this CPU's data
+    let cpu = &mut CPUS[id];
+    cpu.self_ptr = cpu as *mut Cpu as u64;
+    cpu.id = id;
+    Msr::new(MSR_GS_BASE).write(cpu.self_ptr);
+    Msr::new(MSR_KERNEL_GS_BASE).write(0); // user space's until the first swapgs
+}
+
+/// # Safety
+/// GS must point to this CPU's data (see init_cpu), don't keep the reference across a task switch.
+pub unsafe fn current() -> &'static mut Cpu {
+    let cpu: *mut Cpu;
+    asm!("mov {}, gs:[0]", out(reg) cpu);
+    &mut *cpu
+}
+
+pub fn cpu_id() -> usize {
+    unsafe { current().id }
+}
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+fn alloc_idle_stack() -> u64 {
+    let stack: &'static mut [u8] = Vec::leak(alloc::vec![0u8; IDLE_STACK_SIZE]); // never freed
+    (stack.as_ptr() as u64 + IDLE_STACK_SIZE as u64) & !0xf
+}
+
+/// # Safety
+/// Only once at boot on the BSP, after the local APIC and the time base are set up.
+pub unsafe fn init(madt: Option<&Madt>) {
+    // give the BSP its idle stack and start the other CPUs in the MADT, if there's an APIC
+    let bsp = current();
+    bsp.idle_stack_end = alloc_idle_stack();
+    let madt = match madt {
+        Some(madt) => madt,
+        None => return,
+    };
+    bsp.lapic_id = apic::lapic_id();
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    core::ptr::copy_nonoverlapping(start, low_memory(AP_TRAMPOLINE) as *mut u8, len);
+    mem::set_low_identity_map(true); // the APs turn on paging while running in low memory
+    for lapic_id in madt.cpus.iter().copied().filter(|id| *id != bsp.lapic_id) {
+        let id = cpu_count();
+        if id == MAX_CPUS {
+            println!(" - Too many CPUs, only using {}", MAX_CPUS);
+            break;
+        }
+        if start_ap(id, lapic_id) {
+            CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+        } else {
+            println!(" - CPU with local APIC {} didn't start", lapic_id);
+        }
+    }
+    mem::set_low_identity_map(false);
+    println!(" - {} CPUs up", cpu_count());
+}
+
+unsafe fn low_memory(addr: usize) -> usize {
+    mem::PhysAddr::new(addr).to_virt().unwrap().addr()
+}
+
+unsafe fn start_ap(id: usize, lapic_id: u8) -> bool {
+    let cpu = &mut CPUS[id];
+    cpu.lapic_id = lapic_id;
+    cpu.idle_stack_end = alloc_idle_stack(); // it boots on its idle stack
+    let data_off = &ap_trampoline_data as *const u8 as usize - &ap_trampoline_start as *const u8 as usize;
+    let data = low_memory(AP_TRAMPOLINE + data_off) as *mut u64;
+    let efer = Msr::new(MSR_EFER).read() & !(1 << 10); // without LMA, that's set by the CPU
+    data.write_volatile(mem::kernel_p4().addr() as u64);
+    data.add(1).write_volatile(efer);
+    data.add(2).write_volatile(cpu.idle_stack_end);
+    data.add(3).write_volatile(ap_start as *const () as u64);
+    data.add(4).write_volatile(id as u64);
+    AP_READY.store(false, Ordering::SeqCst);
+    serial_println!("SMP: starting CPU {} (local APIC {})", id, lapic_id);
+    apic::send_init(lapic_id);
+    port::pit_wait(11932); // 10 ms
+    for _ in 0..2 {
+        apic::send_startup(lapic_id, (AP_TRAMPOLINE >> 12) as u8);
+        port::pit_wait(239); // 200 us
+    }
+    for _ in 0..100 {
+        if AP_READY.load(Ordering::SeqCst) {
+            return true;
+        }
+        port::pit_wait(11932); // give it a second in total
+    }
+    false
+}
+
+extern "C" fn ap_start(id: usize) -> ! {
+    unsafe {
+        gdt::init_gdt(id);
+        interrupts::setup_idt();
+        mem::enable_nx();
+        usercopy::enable_smep_smap();
+        fpu::init_cpu();
+        syscalls::init_syscalls();
+        apic::init_ap();
+    }
+    AP_READY.store(true, Ordering::SeqCst);
+    println!(" - CPU {} is up", id);
+    unsafe { scheduler::SCHEDULER.run_next() } // runs tasks or waits for them, on the idle stack
+}
+
+pub fn tlb_shootdown(cpus: u32) {
+    // Make the CPUs in the mask (a bit per index in CPUS) flush their TLBs and wait for them. The
+    // caller must not hold any locks they could be spinning on. Interrupts are off throughout so
+    // that nothing else runs here holding SHOOTDOWN, instead waiting for another shootdown answers
+    // the one this CPU might be part of meanwhile.
+    if cpus == 0 {
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _shootdown = loop {
+            if let Some(guard) = SHOOTDOWN.try_lock() {
+                break guard;
+            }
+            ack_shootdown();
+            core::hint::spin_loop();
+        };
+        SHOOTDOWN_PENDING.store(cpus, Ordering::SeqCst);
+        for cpu in (0..cpu_count()).filter(|cpu| cpus & (1 << cpu) != 0) {
+            unsafe { apic::send_fixed(CPUS[cpu].lapic_id, TLB_SHOOTDOWN_VECTOR) };
+        }
+        while SHOOTDOWN_PENDING.load(Ordering::SeqCst) != 0 {
+            core::hint::spin_loop();
+        }
+    })
+}
+
+pub fn ack_shootdown() {
+    // flush this CPU's TLB if a shootdown is waiting for it (from the IPI's handler)
+    let bit = 1 << cpu_id();
+    if SHOOTDOWN_PENDING.load(Ordering::SeqCst) & bit != 0 {
+        x86_64::instructions::tlb::flush_all();
+        SHOOTDOWN_PENDING.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
@@ -1,8 +1,9 @@
 use core::arch::{asm, naked_asm};
-use crate::{fat16, print};
+use crate::{elf, fat16, mem, mmap, print, scheduler, shm, smp, time, usercopy};
 use alloc::vec::Vec;
 use alloc::format;
 use alloc::string::{String, ToString};
+use core::convert::TryFrom;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -11,16 +12,40 @@ const MSR_STAR: usize = 0xc0000081;
 const MSR_LSTAR: usize = 0xc0000082;
 const MSR_FMASK: usize = 0xc0000084;
 
+const MAX_PRINT_LEN: u64 = 0x10000;
+
+// error values returned to userspace (negative like on Linux)
+pub const ENOENT: u64 = -2i64 as u64;
+pub const ESRCH: u64 = -3i64 as u64;
+pub const ENOEXEC: u64 = -8i64 as u64;
+pub const ECHILD: u64 = -10i64 as u64;
+pub const ENOMEM: u64 = -12i64 as u64;
+pub const EFAULT: u64 = -14i64 as u64;
+pub const EEXIST: u64 = -17i64 as u64;
+pub const EINVAL: u64 = -22i64 as u64;
+pub const EROFS: u64 = -30i64 as u64;
+
+// clocks for clock_gettime, both count from boot
+const CLOCK_MONOTONIC: u64 = 1;
+const CLOCK_BOOTTIME: u64 = 7;
+
+// arch_prctl codes
+const ARCH_SET_FS: u64 = 0x1002;
+const ARCH_GET_FS: u64 = 0x1003;
+
 lazy_static! {
     pub static ref STDIN_BUF: Mutex<Option<Vec<u8>>> = Mutex::new(None);
 }
 
+pub static STDIN: scheduler::WaitQueue = scheduler::WaitQueue::new(); // woken by the keyboard when a line is entered
+
 pub unsafe fn init_syscalls() {
     let handler_addr = handle_syscall_wrapper as *const () as u64;
-    // clear Interrupt flag on syscall with AMD's MSR_FSTAR register
+    // clear Interrupt, Direction and Alignment Check (which would disable SMAP) flags on syscall
+    // with AMD's MSR_FSTAR register
     asm!("\
     xor rdx, rdx
-    mov rax, 0x200
+    mov rax, 0x40600
     wrmsr", in("rcx") MSR_FMASK, out("rdx") _);
     // write handler address to AMD's MSR_LSTAR register
     asm!("\
@@ -36,7 +61,18 @@ pub unsafe fn init_syscalls() {
 
 #[inline(never)]
 fn sys_print(str: u64, strlen: u64, i1: u64, i2: u64) -> u64 {
-    let s = unsafe{core::str::from_raw_parts(str as *const u8, strlen as usize)};
+    if strlen > MAX_PRINT_LEN {
+        return EINVAL;
+    }
+    let bytes = match usercopy::read_user_bytes(str as usize, strlen as usize) {
+        Ok(bytes) => bytes,
+        Err(usercopy::UserCopyError::NoMemory) => return ENOMEM,
+        Err(_) => return EFAULT,
+    };
+    let s = match core::str::from_utf8(&bytes) {
+        Ok(s) => s,
+        Err(_) => return EINVAL,
+    };
     if i1 != 0 && i2 != 0 {
         print!("{} {} {}", s, i1, i2);
     } else if i1 != 0 {
@@ -49,55 +85,187 @@ fn sys_print(str: u64, strlen: u64, i1: u64, i2: u64) -> u64 {
 
 #[inline(never)]
 fn sys_getline(str: u64, strlen: u64) -> u64 {
-    if let Some(mut v) = STDIN_BUF.try_lock().take() {
-        if let Some(vv) = v.take() {
-            let strptr = str as *mut u8;
-            let cplen = vv.len().min(strlen as usize);
-            unsafe {strptr.copy_from(vv.as_ptr() as *mut u8, cplen)};
-            return cplen as u64;
-        }
+    if usercopy::validate_user_range(str as usize, strlen as usize).is_err() {
+        return EFAULT; // check before consuming the line
+    }
+    // sleep until the keyboard interrupt fills the buffer, polled with interrupts off
+    let vv = STDIN.wait_event(|| STDIN_BUF.lock().take());
+    let cplen = vv.len().min(strlen as usize);
+    match usercopy::copy_to_user(str as usize, &vv[..cplen]) {
+        Ok(()) => cplen as u64,
+        Err(_) => EFAULT,
     }
-    0
 }
 
 #[inline(never)]
 fn sys_read(inode: u64, out: u64, outlen: u64) -> u64 {
+    if usercopy::validate_user_range(out as usize, outlen as usize).is_err() {
+        return EFAULT; // don't bother reading the disk for a bad buffer
+    }
     let f = fat16::FAT16::new();
-    let outptr = out as *mut u8;
     if let Some(de) = f.at(inode as u16) {
-        if de.is_dir() {
+        let data = if de.is_dir() {
             let mut dir_contents = String::new();
-            f.ls(&de).for_each(|x| {
+            for x in f.ls(&de) {
                 let s = format!("{}: {}", x.name, x.index).to_string(); // make a string with the dir listings
+                if dir_contents.try_reserve(s.len() + 1).is_err() {
+                    return ENOMEM;
+                }
                 dir_contents.push_str(&s);
                 dir_contents.push_str("\n");
-            });
-
-            let cplen = outlen.min(dir_contents.len() as u64);
-            unsafe {outptr.copy_from(dir_contents.as_ptr() as *mut u8, cplen as usize)}; // write the dir contents to the out buffer
-            cplen
+            }
+            dir_contents.into_bytes()
         } else {
-            let data = f.read_data(&de);
-            let cplen = outlen.min(data.len() as u64);
-            unsafe {outptr.copy_from(data.as_ptr() as *mut u8, cplen as usize)}; // write the data to the out buffer
-            cplen
+            match f.read_data(&de) {
+                Ok(data) => data,
+                Err(_) => return ENOMEM,
+            }
+        };
+        let cplen = outlen.min(data.len() as u64);
+        match usercopy::copy_to_user(out as usize, &data[..cplen as usize]) { // write the data to the out buffer
+            Ok(()) => cplen,
+            Err(_) => EFAULT,
         }
     } else {
         0
     }
 }
 
+fn read_user_str(str: u64, strlen: u64, max_len: usize) -> Result<String, u64> {
+    if strlen as usize > max_len {
+        return Err(EINVAL);
+    }
+    let bytes = match usercopy::read_user_bytes(str as usize, strlen as usize) {
+        Ok(bytes) => bytes,
+        Err(usercopy::UserCopyError::NoMemory) => return Err(ENOMEM),
+        Err(_) => return Err(EFAULT),
+    };
+    String::from_utf8(bytes).map_err(|_| EINVAL)
+}
+
+fn shm_error(e: shm::ShmError) -> u64 {
+    match e {
+        shm::ShmError::Exists => EEXIST,
+        shm::ShmError::NotFound => ENOENT,
+        shm::ShmError::InvalidSize | shm::ShmError::BadAddress => EINVAL,
+        shm::ShmError::OutOfMemory => ENOMEM,
+    }
+}
+
+#[inline(never)]
+fn sys_shm_create(name: u64, namelen: u64, size: u64) -> u64 {
+    let name = match read_user_str(name, namelen, shm::MAX_NAME_LEN) {
+        Ok(name) => name,
+        Err(e) => return e,
+    };
+    shm::create(&name, size as usize).map_or_else(shm_error, |id| id as u64)
+}
+
+#[inline(never)]
+fn sys_shm_open(name: u64, namelen: u64) -> u64 {
+    let name = match read_user_str(name, namelen, shm::MAX_NAME_LEN) {
+        Ok(name) => name,
+        Err(e) => return e,
+    };
+    shm::open(&name).map_or_else(shm_error, |id| id as u64)
+}
+
+#[inline(never)]
+fn sys_shm_map(id: u64, addr: u64) -> u64 {
+    // returns the address of the mapping
+    let res = scheduler::SCHEDULER.with_current_space(|space| unsafe { shm::map(space, id as usize, addr as usize) });
+    match res {
+        Some(Ok(addr)) => addr as u64,
+        Some(Err(e)) => shm_error(e),
+        None => EINVAL,
+    }
+}
+
+#[inline(never)]
+fn sys_shm_unmap(addr: u64, len: u64) -> u64 {
+    // returns the number of pages unmapped
+    let res = scheduler::SCHEDULER.with_current_space(|space| unsafe { shm::unmap(space, addr as usize, len as usize) });
+    match res {
+        Some(Ok(pages)) => pages as u64,
+        Some(Err(e)) => shm_error(e),
+        None => EINVAL,
+    }
+}
+
+#[inline(never)]
+fn sys_shm_unlink(name: u64, namelen: u64) -> u64 {
+    let name = match read_user_str(name, namelen, shm::MAX_NAME_LEN) {
+        Ok(name) => name,
+        Err(e) => return e,
+    };
+    shm::unlink(&name).map_or_else(shm_error, |()| 0)
+}
+
+fn mmap_error(e: mmap::MmapError) -> u64 {
+    match e {
+        mmap::MmapError::NotFound => ENOENT,
+        mmap::MmapError::InvalidArgument | mmap::MmapError::BadAddress => EINVAL,
+        mmap::MmapError::OutOfMemory => ENOMEM,
+        mmap::MmapError::ReadOnlyFs => EROFS,
+    }
+}
+
+#[inline(never)]
+fn sys_mmap(inode: u64, offset: u64, len: u64, addr: u64, flags: u64) -> u64 {
+    // returns the address of the mapping
+    if inode > u16::MAX as u64 {
+        return ENOENT;
+    }
+    let file = match fat16::FAT16::new().at(inode as u16) {
+        Some(file) => file, // look it up first, reading the disk can't be done with interrupts off
+        None => return ENOENT,
+    };
+    let res = scheduler::SCHEDULER.with_current_space(|space| unsafe {
+        mmap::mmap(space, &file, offset as usize, len as usize, addr as usize, flags)
+    });
+    match res {
+        Some(Ok(addr)) => addr as u64,
+        Some(Err(e)) => mmap_error(e),
+        None => EINVAL,
+    }
+}
+
+#[inline(never)]
+fn sys_munmap(addr: u64, len: u64) -> u64 {
+    let res = scheduler::SCHEDULER.with_current_space(|space| unsafe { mmap::munmap(space, addr as usize, len as usize) });
+    match res {
+        Some(Ok(())) => 0,
+        Some(Err(e)) => mmap_error(e),
+        None => EINVAL,
+    }
+}
+
+#[inline(never)]
+fn sys_msync(addr: u64, len: u64) -> u64 {
+    let res = scheduler::SCHEDULER.with_current_space(|space| unsafe { mmap::msync(space, addr as usize, len as usize) });
+    match res {
+        Some(Ok(())) => 0,
+        Some(Err(e)) => mmap_error(e),
+        None => EINVAL,
+    }
+}
+
 #[inline(never)]
 fn sys_unhandled() -> u64 {
     panic!("bad syscall number!");
 }
 
 
-// save the registers, handle the syscall and return to usermode
+// switch to the task's kernel stack, save the registers, handle the syscall and return to usermode
 #[naked]
 extern "C" fn handle_syscall_wrapper() {
     unsafe {
         naked_asm!("\
+        swapgs // to this CPU's data
+        mov gs:[{user_rsp}], rsp // interrupts are masked until it's on the kernel stack
+        mov rsp, gs:[{kernel_stack}] // never touch the user stack from the kernel
+        and rsp, -16
+        push qword ptr gs:[{user_rsp}] // keep the user rsp on the kernel stack
         push rcx // backup registers for sysretq
         push r11
         push rbp // save callee-saved registers
@@ -106,12 +274,25 @@ extern "C" fn handle_syscall_wrapper() {
         push r13
         push r14
         push r15
-        mov rbp, rsp // save rsp
-        sub rsp, 0x400 // make some room in the stack
+        push rdi // the user expects all registers except rax, rcx and r11 to be preserved
+        push rsi
+        push rdx
+        push r8
+        push r9
+        push r10
+        sub rsp, 8 // align the stack for the call
         mov rcx, r10 // move fourth syscall arg to rcx which is the fourth argument register in sysv64
-        mov r8, rax // move syscall number to the 5th argument register
-        call {syscall_alloc_stack} // call the handler with the syscall number in r8
-        mov rsp, rbp // restore rsp from rbp
+        mov r9, rax // move syscall number to the 6th argument register, r8 is the fifth syscall arg
+        sti // the task can be preempted (or block) in the kernel, it has its own stack
+        call {handle_syscall} // call the handler with the syscall number in r9
+        cli // no interrupts while on the way out as they would run on the user stack
+        add rsp, 8
+        pop r10
+        pop r9
+        pop r8
+        pop rdx
+        pop rsi
+        pop rdi
         pop r15 // restore callee-saved registers
         pop r14
         pop r13
@@ -120,42 +301,169 @@ extern "C" fn handle_syscall_wrapper() {
         pop rbp // restore stack and registers for sysretq
         pop r11
         pop rcx
+        pop rsp // back to the user stack
+        swapgs // and to user space's gs
         sysretq // back to userland",
-        syscall_alloc_stack = sym syscall_alloc_stack);
+        user_rsp = const smp::CPU_USER_RSP,
+        kernel_stack = const smp::CPU_KERNEL_STACK_END,
+        handle_syscall = sym handle_syscall);
     }
 }
 
-// allocate a temp stack and call the syscall handler
-unsafe extern "sysv64" fn syscall_alloc_stack(arg0: u64, arg1: u64, arg2: u64, arg3: u64, syscall: u64) -> u64 {
-    let syscall_stack: Vec<u8> = Vec::with_capacity(0x10000);
-    let stack_ptr = syscall_stack.as_ptr();
-    let retval = handle_syscall_with_temp_stack(arg0, arg1, arg2, arg3, syscall, stack_ptr);
-    drop(syscall_stack); // we can now drop the syscall temp stack
-    return retval;
+#[inline(never)]
+fn sys_exit(code: u64) -> u64 {
+    // exits the calling thread only, the process's memory goes away with its last one
+    unsafe {
+        scheduler::SCHEDULER.exit_current(code as i64) // doesn't return
+    }
 }
 
 #[inline(never)]
-extern "sysv64" fn handle_syscall_with_temp_stack(arg0: u64, arg1: u64, arg2: u64, arg3: u64, syscall: u64, temp_stack: *const u8) -> u64 {
-    let old_stack: *const u8;
-    unsafe {
-        asm!("\
-        mov {old_stack}, rsp
-        mov rsp, {temp_stack} // move our stack to the newly allocated one
-        sti // enable interrupts",
-        temp_stack = in(reg) temp_stack, old_stack = out(reg) old_stack);
+fn sys_spawn(inode: u64) -> u64 {
+    // start the program in the file as a child of the current task, returns its pid
+    let f = fat16::FAT16::new();
+    let de = match f.at(inode as u16) {
+        Some(de) if !de.is_dir() => de,
+        _ => return ENOENT,
+    };
+    let data = match f.read_data(&de) {
+        Ok(data) => data,
+        Err(_) => return ENOMEM,
+    };
+    match elf::Elf::new(data).and_then(scheduler::Task::try_from) {
+        Ok(task) => match scheduler::SCHEDULER.spawn_child(task) {
+            Ok(pid) => pid as u64,
+            Err(mem::OutOfMemory) => ENOMEM,
+        },
+        Err(elf::ElfError::OutOfMemory) => ENOMEM,
+        Err(_) => ENOEXEC,
+    }
+}
+
+#[inline(never)]
+fn sys_wait(pid: u64) -> u64 {
+    // wait for a child to exit, returns its exit code
+    let res = scheduler::CHILD_EXITED.wait_event(|| scheduler::SCHEDULER.reap_child(pid as usize));
+    match res {
+        Ok(code) => code as u64,
+        Err(()) => ECHILD,
+    }
+}
+
+#[inline(never)]
+fn sys_thread_create(entry: u64, stack_top: u64, arg: u64) -> u64 {
+    // start a thread of the current process at entry(arg) on a stack it allocated, returns its id
+    let user = |addr: u64| (mem::USER_SPACE_START as u64..mem::USER_SPACE_END as u64).contains(&addr);
+    if !user(entry) || !user(stack_top.wrapping_sub(1)) {
+        return EINVAL; // iretq would fault in the kernel on a non-canonical address
+    }
+    match scheduler::SCHEDULER.spawn_thread(entry, stack_top, arg) {
+        Some(Ok(tid)) => tid as u64,
+        Some(Err(_)) => ENOMEM,
+        None => EINVAL,
     }
-    let retval: u64 = match syscall {
+}
+
+#[inline(never)]
+fn sys_thread_join(tid: u64) -> u64 {
+    // wait for another thread of the current process to exit, returns its exit code
+    let res = scheduler::CHILD_EXITED.wait_event(|| scheduler::SCHEDULER.reap_thread(tid as usize));
+    match res {
+        Ok(code) => code as u64,
+        Err(()) => ESRCH,
+    }
+}
+
+#[inline(never)]
+fn sys_arch_prctl(code: u64, addr: u64) -> u64 {
+    // set the FS base of the current thread (for thread local storage) or write it to addr
+    match code {
+        ARCH_SET_FS if addr < mem::USER_SPACE_END as u64 => {
+            unsafe { scheduler::SCHEDULER.set_fs_base(addr) };
+            0
+        }
+        ARCH_GET_FS => match usercopy::copy_to_user(addr as usize, &scheduler::SCHEDULER.fs_base().to_le_bytes()) {
+            Ok(()) => 0,
+            Err(_) => EFAULT,
+        },
+        _ => EINVAL,
+    }
+}
+
+#[inline(never)]
+fn sys_nanosleep(ns: u64) -> u64 {
+    // sleep for ns nanoseconds, rounded up to whole timer ticks
+    match scheduler::sleep_until(time::ticks().saturating_add(time::ns_to_ticks(ns))) {
+        Ok(()) => 0,
+        Err(_) => ENOMEM,
+    }
+}
+
+#[inline(never)]
+fn sys_setpriority(pid: u64, nice: u64) -> u64 {
+    // set the nice value (-20 to 19, clamped) of the current task (pid 0) or of one of its children
+    match scheduler::SCHEDULER.set_nice(pid as usize, nice as i64) {
+        Some(()) => 0,
+        None => ESRCH,
+    }
+}
+
+#[inline(never)]
+fn sys_cpu_idle(out: u64, n: u64) -> u64 {
+    // write the ns each CPU spent idle since boot (u64s) to out, for up to n CPUs, returns the
+    // number of CPUs
+    let count = smp::cpu_count();
+    let mut buf = [0u8; 8 * smp::MAX_CPUS];
+    for cpu in 0..count {
+        buf[cpu * 8..cpu * 8 + 8].copy_from_slice(&scheduler::idle_ns(cpu).to_le_bytes());
+    }
+    let cplen = count.min(n as usize) * 8;
+    match usercopy::copy_to_user(out as usize, &buf[..cplen]) {
+        Ok(()) => count as u64,
+        Err(_) => EFAULT,
+    }
+}
+
+#[inline(never)]
+fn sys_clock_gettime(clock: u64, ts: u64) -> u64 {
+    // writes a timespec (seconds and nanoseconds, both i64) to ts
+    if clock != CLOCK_MONOTONIC && clock != CLOCK_BOOTTIME {
+        return EINVAL; // there's no real time clock yet
+    }
+    let ns = time::uptime_ns();
+    let mut buf = [0u8; 16];
+    buf[..8].copy_from_slice(&(ns / 1_000_000_000).to_le_bytes());
+    buf[8..].copy_from_slice(&(ns % 1_000_000_000).to_le_bytes());
+    match usercopy::copy_to_user(ts as usize, &buf) {
+        Ok(()) => 0,
+        Err(_) => EFAULT,
+    }
+}
+
+// syscalls run with interrupts enabled on the calling task's kernel stack
+extern "sysv64" fn handle_syscall(arg0: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, syscall: u64) -> u64 {
+    match syscall {
         0x1337 => sys_print(arg0, arg1, arg2, arg3),
         0x1338 => sys_getline(arg0, arg1),
         0x8EAD => sys_read(arg0, arg1, arg2),
+        0x3B => sys_spawn(arg0),
+        0x3C => sys_exit(arg0),
+        0x3D => sys_wait(arg0),
+        0x7400 => sys_thread_create(arg0, arg1, arg2),
+        0x7401 => sys_thread_join(arg0),
+        0x9E => sys_arch_prctl(arg0, arg1),
+        0x23 => sys_nanosleep(arg0),
+        0x8D => sys_setpriority(arg0, arg1),
+        0xE4 => sys_clock_gettime(arg0, arg1),
+        0x4300 => sys_cpu_idle(arg0, arg1),
+        0x5400 => sys_shm_create(arg0, arg1, arg2),
+        0x5401 => sys_shm_open(arg0, arg1),
+        0x5402 => sys_shm_map(arg0, arg1),
+        0x5403 => sys_shm_unmap(arg0, arg1),
+        0x5404 => sys_shm_unlink(arg0, arg1),
+        0x4D00 => sys_mmap(arg0, arg1, arg2, arg3, arg4),
+        0x4D01 => sys_munmap(arg0, arg1),
+        0x4D02 => sys_msync(arg0, arg1),
         _ => sys_unhandled(),
-    };
-    unsafe {
-        asm!("\
-        cli // disable interrupts while restoring the stack
-        mov rsp, {old_stack} // restore the old stack
-        ",
-        old_stack = in(reg) old_stack);
     }
-    retval
 }
@@ -0,0 +1,73 @@
+use crate::apic;
+use crate::port;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// The kernel's time base. The local APIC's timer (or the PIT's channel 0 without an APIC) fires
+// the timer interrupt hz times a second, which drives the scheduler and counts ticks. Uptime comes
+// from the TSC when it could be calibrated against the PIT (it's much finer than a tick), else
+// from the tick count.
+
+pub const DEFAULT_TIMER_HZ: u64 = 100;
+const CALIBRATION_MS: u64 = 10;
+
+static TICKS: AtomicU64 = AtomicU64::new(0); // timer interrupts since boot, never goes back
+static TICK_NS: AtomicU64 = AtomicU64::new(1_000_000_000 * 65536 / port::PIT_HZ); // the PIT's default rate until init_time
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0); // 0 if the TSC isn't used
+static TSC_BOOT: AtomicU64 = AtomicU64::new(0); // TSC at init_time
+
+pub fn init_time(hz: u64, apic_timer: Option<apic::TimerMode>) -> u64 {
+    // program the timer and calibrate the TSC, returns the TSC frequency in kHz (0 if unusable)
+    let tick_ns = match apic_timer {
+        Some(mode) => {
+            port::disable_pit();
+            unsafe { apic::start_timer(hz, mode) }
+        }
+        None => match port::set_pit_frequency(hz) {
+            0 => 65536 * 1_000_000_000 / port::PIT_HZ,
+            divisor => divisor as u64 * 1_000_000_000 / port::PIT_HZ,
+        },
+    };
+    TICK_NS.store(tick_ns.max(1), Ordering::SeqCst);
+    let start = rdtsc();
+    port::pit_wait((port::PIT_HZ * CALIBRATION_MS / 1000) as u16);
+    let khz = rdtsc().wrapping_sub(start) / CALIBRATION_MS;
+    TSC_BOOT.store(rdtsc(), Ordering::SeqCst);
+    TSC_KHZ.store(khz, Ordering::SeqCst);
+    khz
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+pub fn tick() -> u64 {
+    // called by the BSP's timer interrupt, returns the new tick count
+    TICKS.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+pub fn tick_ns() -> u64 {
+    TICK_NS.load(Ordering::Relaxed)
+}
+
+pub fn timer_hz() -> u64 {
+    1_000_000_000 / tick_ns()
+}
+
+pub fn uptime_ns() -> u64 {
+    let khz = TSC_KHZ.load(Ordering::Relaxed);
+    if khz == 0 {
+        return ticks() * tick_ns();
+    }
+    let cycles = rdtsc().wrapping_sub(TSC_BOOT.load(Ordering::Relaxed)) as u128;
+    (cycles * 1_000_000 / khz as u128) as u64
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    // rounded up to whole ticks
+    let tick_ns = tick_ns();
+    ns.div_ceil(tick_ns)
+}
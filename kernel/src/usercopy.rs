@@ -0,0 +1,109 @@
+use core::arch::{asm, naked_asm};
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::vec::Vec;
+use crate::mem;
+
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    BadAddress, // the range is not (entirely) inside user space
+    Fault,      // the range is in user space but some page of it isn't mapped as needed
+    NoMemory,   // no kernel memory for the copy
+}
+
+extern "C" {
+    // labels inside user_copy, used by the page fault handler to recover from faulting copies
+    static user_copy_fault_ip: u8;
+    static user_copy_fixup: u8;
+}
+
+/// # Safety
+/// Only at boot on each CPU, after which the kernel may only touch user memory through usercopy.
+pub unsafe fn enable_smep_smap() -> (bool, bool) {
+    let features = core::arch::x86_64::__cpuid_count(7, 0); // structured extended feature flags
+    let smep = features.ebx & (1 << 7) != 0;
+    let smap = features.ebx & (1 << 20) != 0;
+    let mut cr4: u64;
+    asm!("mov {}, cr4", out(reg) cr4);
+    if smep {
+        cr4 |= CR4_SMEP; // kernel can't execute user pages
+    }
+    if smap {
+        cr4 |= CR4_SMAP; // kernel can't access user pages outside of stac / clac
+    }
+    asm!("mov cr4, {}", in(reg) cr4);
+    SMAP_ENABLED.store(smap, Ordering::SeqCst);
+    (smep, smap)
+}
+
+pub fn validate_user_range(addr: usize, len: usize) -> Result<(), UserCopyError> {
+    // the whole range must be inside user space (and must not wrap around)
+    let end = addr.checked_add(len).ok_or(UserCopyError::BadAddress)?;
+    if addr < mem::USER_SPACE_START || end > mem::USER_SPACE_END {
+        Err(UserCopyError::BadAddress)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), UserCopyError> {
+    validate_user_range(src, dst.len())?;
+    let left = unsafe { user_copy(dst.as_mut_ptr(), src as *const u8, dst.len()) };
+    if left == 0 { Ok(()) } else { Err(UserCopyError::Fault) }
+}
+
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UserCopyError> {
+    validate_user_range(dst, src.len())?;
+    let left = unsafe { user_copy(dst as *mut u8, src.as_ptr(), src.len()) };
+    if left == 0 { Ok(()) } else { Err(UserCopyError::Fault) }
+}
+
+pub fn read_user_bytes(src: usize, len: usize) -> Result<Vec<u8>, UserCopyError> {
+    validate_user_range(src, len)?; // check before allocating anything for the copy
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).map_err(|_| UserCopyError::NoMemory)?; // len comes from user space
+    buf.resize(len, 0);
+    copy_from_user(&mut buf, src)?;
+    Ok(buf)
+}
+
+pub fn fault_fixup(rip: usize) -> Option<usize> {
+    // if the faulting instruction is the user copy, continue at its fixup label which
+    // returns the number of bytes left to copy instead of taking the kernel down
+    unsafe {
+        if rip == &user_copy_fault_ip as *const u8 as usize {
+            Some(&user_copy_fixup as *const u8 as usize)
+        } else {
+            None
+        }
+    }
+}
+
+// copy len bytes between kernel and user memory and return how many bytes were not copied
+#[naked]
+#[allow(named_asm_labels)]
+unsafe extern "sysv64" fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    naked_asm!("\
+    cmp byte ptr [rip + {smap}], 0
+    je 2f
+    stac // allow supervisor access to user pages for this copy only
+    2:
+    cld
+    mov rcx, rdx
+    .global user_copy_fault_ip
+    user_copy_fault_ip:
+    rep movsb // on a fault we resume below with rcx = bytes left
+    .global user_copy_fixup
+    user_copy_fixup:
+    cmp byte ptr [rip + {smap}], 0
+    je 3f
+    clac
+    3:
+    mov rax, rcx
+    ret",
+    smap = sym SMAP_ENABLED);
+}
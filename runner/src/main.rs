@@ -21,6 +21,9 @@ use rust_os::interrupts::setup_idt;
 use rust_os::mem;
 use rust_os::mem::FRAME_SIZE;
 use rust_os::port::init_pics;
+use rust_os::sched_policy::{FairShare, PriorityRoundRobin, SchedParams, SchedPolicy};
+use rust_os::slab_alloc::SlabAllocator;
+use rust_os::time;
 use rust_os::vga_buffer::{cls, WRITER};
 use rust_os::{println, serial_println};
 use spin::Mutex;
@@ -119,7 +122,7 @@ fn test_timer() {
     let line = WRITER.lock().get_line(0);
     assert!(!line.contains(&('.' as u8)));
     serial_println!("Line starts out with no dots...");
-    init_pics();
+    init_pics(false); // legacy PICs, no APIC set up here
     for _ in 0..1000000 {}
     let line = WRITER.lock().get_line(0);
     assert!(line.contains(&('.' as u8)));
@@ -137,7 +140,7 @@ fn test_paging_table() {
     let phys = mem::PhysAddr::new(0x1000000);
     let virt = unsafe { phys.to_virt().unwrap() };
     serial_println!("Testing {} phys to virt: {}", phys, virt);
-    assert_eq!(virt.addr(), 0xC1000000);
+    assert_eq!(virt.addr(), 0xFFFF800001000000);
     let (phys, pte) = unsafe { virt.to_phys().unwrap() };
     serial_println!("Testing virt {} back to phys: {}", virt, phys);
     assert_eq!(phys.addr(), 0x1000000);
@@ -277,3 +280,147 @@ fn test_global_allocator() {
     }
     serial_println!("[x] Test passed!");
 }
+
+#[test_case]
+fn test_slab_allocator() {
+    cls();
+    serial_println!("Creating new slab allocator on top of a single page buddy allocator");
+    unsafe {
+        let mut allocator = get_frame_allocator();
+        let first_page = allocator.allocate().unwrap();
+        DUMMY_ALLOCATOR.replace(allocator);
+        global_alloc::init_allocator_info(DUMMY_ALLOCATOR.as_mut().unwrap());
+        let buddy_alloc_manager = BuddyAllocatorManager::new();
+        buddy_alloc_manager.add_memory_area(first_page, first_page.offset(FRAME_SIZE), 16);
+        let slab = SlabAllocator::new();
+        let layout_16 = Layout::from_size_align(12, 4).unwrap();
+        let obj_1 = slab.alloc(layout_16, &buddy_alloc_manager);
+        let obj_2 = slab.alloc(layout_16, &buddy_alloc_manager);
+        serial_println!("Slab objects must come from the page given by the buddy allocator: {:?} - {}", obj_1, first_page.to_virt().unwrap());
+        assert_eq!(obj_1 as usize, first_page.to_virt().unwrap().addr());
+        let diff = obj_2 as usize - obj_1 as usize;
+        serial_println!("16-byte objects must be 16 bytes apart: {:?} - {:?} = {}", obj_2, obj_1, diff);
+        assert_eq!(diff, 16);
+        slab.dealloc(obj_1, layout_16);
+        let obj_3 = slab.alloc(layout_16, &buddy_alloc_manager);
+        serial_println!("Freed object should be reused: {:?} == {:?}", obj_1, obj_3);
+        assert_eq!(obj_1, obj_3);
+        // the only page is taken by the 16-byte class so a different class can't get one
+        let obj_64 = slab.alloc(Layout::from_size_align(64, 8).unwrap(), &buddy_alloc_manager);
+        serial_println!("64-byte class should be out of memory: {:?}", obj_64);
+        assert!(obj_64.is_null());
+        serial_println!("{}", slab);
+        let stats = slab.stats();
+        assert_eq!(stats[0].pages, 1);
+        assert_eq!(stats[0].in_use, 2);
+    }
+    serial_println!("[x] Test passed!");
+}
+
+#[test_case]
+fn test_buddy_stats() {
+    cls();
+    serial_println!("Testing: buddy allocator statistics...");
+    unsafe {
+        let mut allocator = get_frame_allocator();
+        let first_page = allocator.allocate().unwrap();
+        DUMMY_ALLOCATOR.replace(allocator);
+        global_alloc::init_allocator_info(DUMMY_ALLOCATOR.as_mut().unwrap());
+        let buddy_alloc_manager = BuddyAllocatorManager::new();
+        buddy_alloc_manager.add_memory_area(first_page, first_page.offset(FRAME_SIZE), 16);
+        let blk_100 = buddy_alloc_manager.alloc(Layout::from_size_align(100, 4).unwrap());
+        let blk_16 = buddy_alloc_manager.alloc(Layout::from_size_align(16, 4).unwrap());
+        let stats = buddy_alloc_manager.stats()[0];
+        serial_println!("Allocated 100 and 16 bytes: {:?}", stats);
+        // 100 bytes are rounded up to a 128 block
+        assert_eq!(stats.allocated_bytes, 128 + 16);
+        assert_eq!(stats.free_bytes, FRAME_SIZE - 128 - 16);
+        assert_eq!(stats.allocs, 2);
+        buddy_alloc_manager.dealloc(blk_100, Layout::from_size_align(100, 4).unwrap());
+        buddy_alloc_manager.dealloc(blk_16, Layout::from_size_align(16, 4).unwrap());
+        let big = buddy_alloc_manager.alloc(Layout::from_size_align(2 * FRAME_SIZE, 4).unwrap());
+        assert!(big.is_null());
+        buddy_alloc_manager.dump();
+        let stats = buddy_alloc_manager.stats()[0];
+        serial_println!("After freeing everything: {:?}", stats);
+        assert_eq!(stats.allocated_bytes, 0);
+        assert_eq!(stats.peak_bytes, 128 + 16);
+        // everything was merged back into a single block
+        assert_eq!(stats.largest_free, FRAME_SIZE);
+        assert_eq!(stats.fragmentation(), 0);
+        assert_eq!(buddy_alloc_manager.failed_allocs(), 1);
+    }
+    serial_println!("[x] Test passed!");
+}
+
+#[test_case]
+fn test_fair_policy() {
+    cls();
+    serial_println!("Testing: fair scheduling policy...");
+    unsafe {
+        DUMMY_ALLOCATOR.replace(get_frame_allocator());
+        global_alloc::init_allocator_info(DUMMY_ALLOCATOR.as_mut().unwrap());
+    }
+    let mut policy = FairShare::new();
+    policy.reserve(3).unwrap();
+    let mut nice_0 = SchedParams { nice: 0, vruntime: 0 };
+    let mut nice_5 = SchedParams { nice: 5, vruntime: 0 };
+    // the same time on the CPU counts for more the higher the nice value
+    policy.ran(&mut nice_0, 1_000_000_000);
+    policy.ran(&mut nice_5, 1_000_000_000);
+    serial_println!("vruntime after 1s: nice 0 {} - nice 5 {}", nice_0.vruntime, nice_5.vruntime);
+    assert_eq!(nice_0.vruntime, 1_000_000_000);
+    assert_eq!(nice_5.vruntime, 1_000_000_000 * 1024 / 335);
+    policy.enqueue(2, &mut nice_5);
+    policy.enqueue(1, &mut nice_0);
+    assert_eq!(policy.len(), 2);
+    // the task that ran the least (weighted) goes first
+    assert_eq!(policy.pick_next(), Some(1));
+    assert_eq!(policy.pick_next(), Some(2));
+    assert_eq!(policy.pick_next(), None);
+    // a new task starts a tick behind the last one picked instead of at 0
+    let mut new = SchedParams::default();
+    policy.enqueue(3, &mut new);
+    serial_println!("New task's vruntime: {}", new.vruntime);
+    assert_eq!(new.vruntime, nice_5.vruntime - time::tick_ns());
+    // but one that's further ahead keeps its own
+    let mut ahead = SchedParams { nice: 0, vruntime: nice_5.vruntime * 2 };
+    policy.enqueue(4, &mut ahead);
+    assert_eq!(ahead.vruntime, nice_5.vruntime * 2);
+    policy.remove(3);
+    assert_eq!(policy.pick_next(), Some(4));
+    serial_println!("[x] Test passed!");
+}
+
+#[test_case]
+fn test_priority_policy() {
+    cls();
+    serial_println!("Testing: priority round-robin scheduling policy...");
+    unsafe {
+        DUMMY_ALLOCATOR.replace(get_frame_allocator());
+        global_alloc::init_allocator_info(DUMMY_ALLOCATOR.as_mut().unwrap());
+    }
+    let mut policy = PriorityRoundRobin::new();
+    policy.reserve(4).unwrap();
+    let mut params = [
+        SchedParams { nice: 0, vruntime: 0 },
+        SchedParams { nice: 0, vruntime: 0 },
+        SchedParams { nice: 0, vruntime: 0 },
+        SchedParams { nice: -5, vruntime: 0 },
+    ];
+    for (pid, params) in params.iter_mut().enumerate() {
+        policy.enqueue(pid, params);
+    }
+    // the most favoured task runs first, the rest in the order they were enqueued
+    assert_eq!(policy.pick_next(), Some(3));
+    assert_eq!(policy.pick_next(), Some(0));
+    // a task that goes back to the queue waits for the others of its nice value
+    policy.enqueue(0, &mut params[0]);
+    assert_eq!(policy.pick_next(), Some(1));
+    policy.enqueue(3, &mut params[3]);
+    assert_eq!(policy.pick_next(), Some(3));
+    assert_eq!(policy.pick_next(), Some(2));
+    assert_eq!(policy.pick_next(), Some(0));
+    assert_eq!(policy.pick_next(), None);
+    serial_println!("[x] Test passed!");
+}
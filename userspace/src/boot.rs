@@ -1,7 +1,23 @@
 #![no_std]
 #![no_main]
+use core::arch::asm;
 use userspace::*;
 
+const WORKERS: usize = 4;
+static mut WORKER_STACKS: [[u8; 0x4000]; WORKERS] = [[0; 0x4000]; WORKERS];
+static mut WORKER_SUMS: [u64; WORKERS] = [0; WORKERS];
+
+extern "C" fn sum_squares(n: u64) -> ! {
+    // a worker thread of the threads command, it leaves its sum in its slot which is its FS base
+    unsafe { set_fs_base(&raw mut WORKER_SUMS[n as usize] as u64) };
+    let mut sum = 0u64;
+    for i in n * 1000..(n + 1) * 1000 {
+        sum += i * i;
+    }
+    unsafe { asm!("mov qword ptr fs:[0], {}", in(reg) sum) };
+    thread_exit(n as i64)
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn _start() {
     let mut buf = [0u8; 1024];
@@ -15,6 +31,12 @@ extern "C" fn _start() {
         if prefix(s, "help") {
             printf("echo x -> print x\n", 0, 0);
             printf("read fno -> read file / list dir with this file no (root is 0)\n", 0, 0);
+            printf("run fno -> run the program in this file no and wait for it\n", 0, 0);
+            printf("nice n fno -> run the program in this file no at nice value n (-20 to 19)\n", 0, 0);
+            printf("sleep ms -> sleep for this many milliseconds\n", 0, 0);
+            printf("uptime -> show the ms since boot\n", 0, 0);
+            printf("cpu -> show how busy each CPU was since boot\n", 0, 0);
+            printf("threads -> sum squares in a few threads\n", 0, 0);
             printf("help -> show this\n", 0, 0);
             printf("exit -> shut down\n", 0, 0);
         } else if prefix(s, "echo ") {
@@ -32,12 +54,82 @@ extern "C" fn _start() {
                     printf("Bad inode no", 0, 0);
                 }
             }
+        } else if prefix(s, "run ") {
+            match s[4..].parse::<u64>() {
+                Ok(inode) => {
+                    let pid = spawn(inode);
+                    if pid < 0 {
+                        printf("Could not run it, error", (-pid) as u64, 0);
+                    } else {
+                        let code = wait(pid as u64);
+                        printf("Exited with code", code as u64, 0);
+                    }
+                },
+                Err(_) => {
+                    printf("Bad inode no", 0, 0);
+                }
+            }
+        } else if prefix(s, "nice ") {
+            let mut args = s[5..].split_whitespace().map(|arg| arg.parse::<i64>());
+            match (args.next(), args.next()) {
+                (Some(Ok(nice)), Some(Ok(inode))) if inode >= 0 => {
+                    let pid = spawn(inode as u64);
+                    if pid < 0 {
+                        printf("Could not run it, error", (-pid) as u64, 0);
+                    } else {
+                        setpriority(pid as u64, nice);
+                        let code = wait(pid as u64);
+                        printf("Exited with code", code as u64, 0);
+                    }
+                },
+                _ => {
+                    printf("Usage: nice n fno", 0, 0);
+                }
+            }
+        } else if prefix(s, "sleep ") {
+            match s[6..].parse::<u64>() {
+                Ok(ms) => sleep(ms),
+                Err(_) => {
+                    printf("Bad number of ms", 0, 0);
+                }
+            }
+        } else if prefix(s, "uptime") {
+            printf("Up for ms:", uptime_ms(), 0);
+        } else if prefix(s, "cpu") {
+            let mut idle_ns = [0u64; 16];
+            let uptime_ns = uptime_ms().max(1) * 1_000_000;
+            let count = cpu_idle(&mut idle_ns).min(idle_ns.len());
+            let mut num = [0u8; 20];
+            for cpu in 0..count {
+                let busy = 100 - (idle_ns[cpu] / (uptime_ns / 100).max(1)).min(100);
+                printf("CPU ", 0, 0);
+                printf(u64_to_str(cpu as u64, &mut num), 0, 0);
+                printf(" busy: ", 0, 0);
+                printf(u64_to_str(busy, &mut num), 0, 0);
+                printf("%\n", 0, 0);
+            }
+        } else if prefix(s, "threads") {
+            let mut tids = [0i64; WORKERS];
+            for n in 0..WORKERS {
+                let stack = unsafe { &mut *(&raw mut WORKER_STACKS[n]) };
+                tids[n] = thread_create(sum_squares, stack, n as u64);
+            }
+            let mut total = 0;
+            for n in 0..WORKERS {
+                if tids[n] < 0 {
+                    printf("Could not start a thread, error", (-tids[n]) as u64, 0);
+                } else if thread_join(tids[n] as u64) == n as i64 {
+                    total += unsafe { WORKER_SUMS[n] };
+                }
+            }
+            let mut num = [0u8; 20];
+            printf("Sum of squares below 4000: ", 0, 0);
+            printf(u64_to_str(total, &mut num), 0, 0);
         } else if prefix(s, "exit") {
             break;
         } else {
             printf("Unknown command, use help to see cmds", 0, 0);
         }
-
-        sleep(10000);
     }
+    exit(0);
 }
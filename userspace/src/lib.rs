@@ -29,23 +29,85 @@ pub fn syscall(
     ret
 }
 
+#[inline(never)]
+pub fn syscall5(
+    n: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> u64 {
+    let mut ret: u64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") n as u64 => ret,
+            in("rdi") arg1,
+            in("rsi") arg2,
+            in("rdx") arg3,
+            in("r10") arg4,
+            in("r8") arg5,
+            out("rcx") _,
+            out("r11") _,
+            options(nostack, preserves_flags)
+        );
+    }
+    ret
+}
+
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     loop {}
 }
 
-pub fn sleep(c: u64) {
-    for _ in 0..c {
-        unsafe {
-            asm!("nop");
-        }
-    }
+pub fn nanosleep(ns: u64) -> i64 {
+    syscall(0x23, ns, 0, 0, 0) as i64
+}
+
+// clocks for clock_gettime, both count from boot
+pub const CLOCK_MONOTONIC: u64 = 1;
+pub const CLOCK_BOOTTIME: u64 = 7;
+
+pub fn clock_gettime(clock: u64, ts: &mut [i64; 2]) -> i64 {
+    // ts gets the seconds and nanoseconds
+    syscall(0xE4, clock, ts.as_mut_ptr() as u64, 0, 0) as i64
+}
+
+pub fn uptime_ms() -> u64 {
+    let mut ts = [0i64; 2];
+    clock_gettime(CLOCK_MONOTONIC, &mut ts);
+    ts[0] as u64 * 1000 + ts[1] as u64 / 1_000_000
+}
+
+pub fn cpu_idle(idle_ns: &mut [u64]) -> usize {
+    // the ns each CPU spent idle since boot, returns the number of CPUs (which can be more)
+    syscall(0x4300, idle_ns.as_mut_ptr() as u64, idle_ns.len() as u64, 0, 0) as usize
+}
+
+pub fn sleep(ms: u64) {
+    // the kernel parks the task until then, no spinning
+    nanosleep(ms.saturating_mul(1_000_000));
 }
 
 pub fn printf(str: &str, a1: u64, a2: u64) -> u64 {
     syscall(0x1337, str.as_ptr() as *const u8 as u64, str.len() as u64, a1, a2)
 }
 
+pub fn u64_to_str(mut n: u64, buf: &mut [u8; 20]) -> &str {
+    // printf leaves out zeroes, this doesn't
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    bytes_to_str(&buf[i..], buf.len() - i)
+}
+
 pub fn bytes_to_str(b: &[u8], l: usize) -> &str {
     unsafe {
         str::from_raw_parts(b.as_ptr(), l)
@@ -76,6 +138,82 @@ pub fn readi(inode: u64, out: &mut [u8]) -> usize {
     syscall(0x8EAD, inode, out.as_ptr() as u64, out.len() as u64, 0) as usize
 }
 
+// tasks, spawn returns the pid of the new task and wait its exit code (errors are negative)
+pub fn exit(code: i64) -> ! {
+    syscall(0x3C, code as u64, 0, 0, 0);
+    loop {}
+}
+
+pub fn spawn(inode: u64) -> i64 {
+    syscall(0x3B, inode, 0, 0, 0) as i64
+}
+
+pub fn wait(pid: u64) -> i64 {
+    syscall(0x3D, pid, 0, 0, 0) as i64
+}
+
+pub fn setpriority(pid: u64, nice: i64) -> i64 {
+    // -20 (most favoured) to 19, for this task (pid 0) or one of its children
+    syscall(0x8D, pid, nice as u64, 0, 0) as i64
+}
+
+// threads of the current process, they share its memory but each needs a stack of its own
+pub fn thread_create(f: extern "C" fn(u64) -> !, stack: &'static mut [u8], arg: u64) -> i64 {
+    let stack_top = stack.as_mut_ptr() as u64 + stack.len() as u64;
+    syscall(0x7400, f as *const () as u64, stack_top, arg, 0) as i64
+}
+
+pub fn thread_join(tid: u64) -> i64 {
+    syscall(0x7401, tid, 0, 0, 0) as i64
+}
+
+pub fn thread_exit(code: i64) -> ! {
+    exit(code) // only ever exits the calling thread
+}
+
+pub fn set_fs_base(addr: u64) -> i64 {
+    // for thread local storage, fs:[0] is at addr in the calling thread
+    syscall(0x9E, 0x1002, addr, 0, 0) as i64
+}
+
+// shared memory objects, errors are returned as negative values
+pub fn shm_create(name: &str, size: u64) -> i64 {
+    syscall(0x5400, name.as_ptr() as u64, name.len() as u64, size, 0) as i64
+}
+
+pub fn shm_open(name: &str) -> i64 {
+    syscall(0x5401, name.as_ptr() as u64, name.len() as u64, 0, 0) as i64
+}
+
+pub fn shm_map(id: u64, addr: u64) -> i64 {
+    syscall(0x5402, id, addr, 0, 0) as i64
+}
+
+pub fn shm_unmap(addr: u64, len: u64) -> i64 {
+    syscall(0x5403, addr, len, 0, 0) as i64
+}
+
+pub fn shm_unlink(name: &str) -> i64 {
+    syscall(0x5404, name.as_ptr() as u64, name.len() as u64, 0, 0) as i64
+}
+
+// memory mapped files, flags are MAP_SHARED or MAP_PRIVATE, optionally with PROT_WRITE
+pub const MAP_SHARED: u64 = 1;
+pub const MAP_PRIVATE: u64 = 2;
+pub const PROT_WRITE: u64 = 4;
+
+pub fn mmap(inode: u64, offset: u64, len: u64, addr: u64, flags: u64) -> i64 {
+    syscall5(0x4D00, inode, offset, len, addr, flags) as i64
+}
+
+pub fn munmap(addr: u64, len: u64) -> i64 {
+    syscall(0x4D01, addr, len, 0, 0) as i64
+}
+
+pub fn msync(addr: u64, len: u64) -> i64 {
+    syscall(0x4D02, addr, len, 0, 0) as i64
+}
+
 #[unsafe(no_mangle)]
 pub fn memset(s: &mut [u8], c: u8) {
     for i in 0..s.len() {